@@ -0,0 +1,242 @@
+use crate::{
+    homeassistant::EntityType,
+    messages::{Data, Hello},
+    metrics::Metrics,
+    sink::{InverterInfo, LoggerInfo, Sink},
+    state::{HelloInfo, InverterSnapshot, LoggerState},
+};
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Latest readings of every inverter, served over the REST API.
+///
+/// Cloning is cheap, all clones share the same readings.
+#[derive(Clone)]
+pub struct Readings {
+    inverters: Arc<Mutex<BTreeMap<String, InverterSnapshot>>>,
+    hellos: Arc<Mutex<HashMap<u32, HelloInfo>>>,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+struct InverterSummary {
+    serial: String,
+    data_logger_sn: u32,
+    name: String,
+    last_seen: u64,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    version: &'static str,
+    uptime: u64,
+    connections: i64,
+    inverters: usize,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    readings: Readings,
+    metrics: Metrics,
+}
+
+impl Readings {
+    pub fn new() -> Self {
+        Readings {
+            inverters: Arc::default(),
+            hellos: Arc::default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Serves the last known state until the data loggers report again.
+    pub fn restore(&self, loggers: &HashMap<u32, LoggerState>) {
+        let mut inverters = self.inverters.lock().unwrap();
+        let mut hellos = self.hellos.lock().unwrap();
+
+        for (data_logger_sn, state) in loggers {
+            if let Some(snapshot) = &state.inverter {
+                inverters.insert(snapshot.serial.clone(), snapshot.clone());
+            }
+            if let Some(hello) = &state.hello {
+                hellos.insert(*data_logger_sn, hello.clone());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for Readings {
+    async fn on_hello(&self, logger: &LoggerInfo, hello: &Hello) -> anyhow::Result<()> {
+        self.hellos.lock().unwrap().insert(
+            logger.data_logger_sn,
+            HelloInfo::new(logger.data_logger_sn, hello),
+        );
+        Ok(())
+    }
+
+    async fn on_data(
+        &self,
+        inverter: &InverterInfo,
+        data: &Data,
+        _entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        let snapshot = InverterSnapshot::new(inverter, data)?;
+
+        self.inverters
+            .lock()
+            .unwrap()
+            .insert(inverter.serial.clone(), snapshot);
+        Ok(())
+    }
+}
+
+/// Routes of the REST API under `/api`.
+pub fn router(readings: Readings, metrics: Metrics) -> Router {
+    Router::new()
+        .route("/api/health", get(health))
+        .route("/api/inverters", get(inverters))
+        .route("/api/inverters/:serial", get(inverter))
+        .route("/api/inverters/:serial/hello", get(hello))
+        .with_state(ApiState { readings, metrics })
+}
+
+async fn health(State(state): State<ApiState>) -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: state.readings.started.elapsed().as_secs(),
+        connections: state.metrics.connections(),
+        inverters: state.readings.inverters.lock().unwrap().len(),
+    })
+}
+
+async fn inverters(State(state): State<ApiState>) -> Json<Vec<InverterSummary>> {
+    let inverters = state.readings.inverters.lock().unwrap();
+
+    Json(
+        inverters
+            .iter()
+            .map(|(serial, readings)| InverterSummary {
+                serial: serial.clone(),
+                data_logger_sn: readings.data_logger_sn,
+                name: readings.name.clone(),
+                last_seen: readings.last_seen,
+            })
+            .collect(),
+    )
+}
+
+async fn inverter(
+    State(state): State<ApiState>,
+    Path(serial): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let inverters = state.readings.inverters.lock().unwrap();
+
+    inverters
+        .get(&serial.trim().to_lowercase())
+        .map(|readings| Json(readings.data.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn hello(
+    State(state): State<ApiState>,
+    Path(serial): Path<String>,
+) -> Result<Json<HelloInfo>, StatusCode> {
+    let data_logger_sn = state
+        .readings
+        .inverters
+        .lock()
+        .unwrap()
+        .get(&serial.trim().to_lowercase())
+        .map(|readings| readings.data_logger_sn)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    state
+        .readings
+        .hellos
+        .lock()
+        .unwrap()
+        .get(&data_logger_sn)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use bytes::BytesMut;
+    use hyper::{body, Client, StatusCode};
+    use serde_json::Value;
+    use tokio_util::codec::Decoder;
+
+    use super::{router, Readings};
+    use crate::{
+        codec::{SofarCodec, DATA},
+        messages::IncomingMessageData,
+        metrics::Metrics,
+        sink::{tests::inverter, Sink},
+    };
+
+    async fn get(url: String) -> (StatusCode, Value) {
+        let response = Client::new().get(url.parse().unwrap()).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn serves_latest_readings() {
+        let readings = Readings::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router(readings.clone(), Metrics::new()).into_make_service()),
+        );
+
+        let message = SofarCodec::default()
+            .decode(&mut BytesMut::from(&DATA[..]))
+            .unwrap()
+            .unwrap();
+        let IncomingMessageData::Data(data) = message.data else {
+            panic!("expected data frame");
+        };
+        let inverter = inverter();
+        readings.on_data(&inverter, &data, &[]).await.unwrap();
+
+        let (status, health) = get(format!("{url}/api/health")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["inverters"], 1);
+
+        let (_, inverters) = get(format!("{url}/api/inverters")).await;
+        assert_eq!(inverters[0]["serial"], "sf4es003m4c058");
+
+        let (status, data) = get(format!("{url}/api/inverters/SF4ES003M4C058")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(data["current_power"], 310);
+
+        let (status, _) = get(format!("{url}/api/inverters/sf4es003m4c058/hello")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(format!("{url}/api/inverters/unknown")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
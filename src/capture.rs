@@ -0,0 +1,260 @@
+use anyhow::{anyhow, ensure, Context};
+use bytes::{Buf, BufMut};
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    task::{ready, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, oneshot},
+    task,
+};
+use tracing::error;
+
+/// Capture files start with this marker, followed by the records.
+const MAGIC: &[u8; 8] = b"SOFARCAP";
+
+/// Bytes received from a data logger in a single read.
+///
+/// Stored as `u64` milliseconds since epoch, `u16` length and text of the peer
+/// address, `u32` length and the data itself, all little endian. Reads are
+/// stored as received, so noise and partial frames are replayed faithfully.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub timestamp: u64,
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+impl CaptureRecord {
+    fn to_bytes(&self) -> Vec<u8> {
+        let peer = self.peer.to_string();
+        let mut buf = Vec::with_capacity(8 + 2 + peer.len() + 4 + self.data.len());
+
+        buf.put_u64_le(self.timestamp);
+        buf.put_u16_le(peer.len() as u16);
+        buf.put_slice(peer.as_bytes());
+        buf.put_u32_le(self.data.len() as u32);
+        buf.put_slice(&self.data);
+        buf
+    }
+
+    fn from_bytes(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        ensure!(buf.remaining() >= 8 + 2, "Truncated capture record");
+        let timestamp = buf.get_u64_le();
+        let peer_length = usize::from(buf.get_u16_le());
+
+        ensure!(
+            buf.remaining() >= peer_length + 4,
+            "Truncated capture record"
+        );
+        let peer = std::str::from_utf8(&buf[..peer_length])?
+            .parse()
+            .context("Invalid peer address in capture record")?;
+        buf.advance(peer_length);
+        let data_length = buf.get_u32_le() as usize;
+
+        ensure!(buf.remaining() >= data_length, "Truncated capture record");
+        let data = buf[..data_length].to_vec();
+        buf.advance(data_length);
+
+        Ok(CaptureRecord {
+            timestamp,
+            peer,
+            data,
+        })
+    }
+}
+
+/// Handle appending records to a capture file.
+///
+/// Records are written by a background task, so capturing never blocks the
+/// connection handlers.
+#[derive(Clone)]
+pub struct Capture {
+    sender: mpsc::UnboundedSender<CaptureMessage>,
+}
+
+enum CaptureMessage {
+    Record(CaptureRecord),
+    /// Answered once all records sent before are written
+    Flush(oneshot::Sender<()>),
+}
+
+impl Capture {
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Error opening capture file {}", path.display()))?;
+
+        if file.metadata().await?.len() == 0 {
+            file.write_all(MAGIC).await?;
+            file.flush().await?;
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<CaptureMessage>();
+
+        task::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let record = match message {
+                    CaptureMessage::Record(record) => record,
+                    CaptureMessage::Flush(done) => {
+                        // records are flushed as they are written
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                let result = async {
+                    file.write_all(&record.to_bytes()).await?;
+                    file.flush().await
+                }
+                .await;
+
+                if let Err(err) = result {
+                    error!("Error writing capture file {} ({err})", path.display());
+                    return;
+                }
+            }
+        });
+
+        Ok(Capture { sender })
+    }
+
+    pub fn record(&self, peer: SocketAddr, data: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+
+        // writer task only stops on errors, which it already reported
+        let _ = self.sender.send(CaptureMessage::Record(CaptureRecord {
+            timestamp,
+            peer,
+            data: data.to_vec(),
+        }));
+    }
+
+    /// Waits until all records captured so far are written to the file.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (done, flushed) = oneshot::channel();
+
+        self.sender
+            .send(CaptureMessage::Flush(done))
+            .map_err(|_| anyhow!("Capture writer stopped"))?;
+        flushed.await.map_err(|_| anyhow!("Capture writer stopped"))
+    }
+}
+
+/// Reads all records of a capture file.
+pub fn read_capture(path: impl AsRef<Path>) -> anyhow::Result<Vec<CaptureRecord>> {
+    let path = path.as_ref();
+    let content = std::fs::read(path)
+        .with_context(|| format!("Error reading capture file {}", path.display()))?;
+
+    let mut buf = content
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow!("{} is not a capture file", path.display()))?;
+    let mut records = vec![];
+
+    while buf.has_remaining() {
+        records.push(CaptureRecord::from_bytes(&mut buf)?);
+    }
+
+    Ok(records)
+}
+
+/// Stream passing everything it reads to the capture, if there is one.
+pub struct CapturedStream<S> {
+    inner: S,
+    peer: SocketAddr,
+    capture: Option<Capture>,
+}
+
+impl<S> CapturedStream<S> {
+    pub fn new(inner: S, peer: SocketAddr, capture: Option<Capture>) -> Self {
+        CapturedStream {
+            inner,
+            peer,
+            capture,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CapturedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if let Some(capture) = &self.capture {
+            if buf.filled().len() > filled {
+                capture.record(self.peer, &buf.filled()[filled..]);
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CapturedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{read_capture, Capture, CapturedStream};
+
+    #[tokio::test]
+    async fn captures_received_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logger.cap");
+        let peer = "10.0.0.64:51234".parse().unwrap();
+
+        let capture = Capture::open(&path).await.unwrap();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = CapturedStream::new(server, peer, Some(capture.clone()));
+
+        client.write_all(&[165, 1, 0]).await.unwrap();
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        capture.flush().await.unwrap();
+
+        let records = read_capture(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer, peer);
+        assert_eq!(records[0].data, vec![165, 1, 0]);
+    }
+}
@@ -0,0 +1,166 @@
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Receives data frames from Sofar data loggers and publishes them to MQTT.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file, TOML or YAML depending on the extension
+    #[arg(short, long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration, with secrets redacted, and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Feed frames from a capture file through the publishing pipeline
+    Replay {
+        /// File written with the `capture_file` option
+        file: PathBuf,
+    },
+}
+
+/// Options overriding the configuration file, command line takes precedence
+/// over environment variables.
+#[derive(Args, Serialize, Debug, Default)]
+pub struct ConfigOverrides {
+    /// MQTT broker host
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_host: Option<String>,
+
+    /// MQTT broker port
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_port: Option<u16>,
+
+    /// MQTT user name
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_user: Option<String>,
+
+    /// MQTT password
+    #[arg(long, env, hide_env_values = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_password: Option<String>,
+
+    /// Connect to the MQTT broker over TLS
+    #[arg(long, env, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_tls: Option<bool>,
+
+    /// CA certificate used to verify the MQTT broker
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_ca_file: Option<String>,
+
+    /// Client certificate for the MQTT connection
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_client_cert_file: Option<String>,
+
+    /// Client private key for the MQTT connection
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_client_key_file: Option<String>,
+
+    /// Skip MQTT broker certificate verification
+    #[arg(long, env, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_tls_insecure: Option<bool>,
+
+    /// Port accepting data logger connections
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_port: Option<u16>,
+
+    /// Home Assistant discovery prefix
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_discovery_prefix: Option<String>,
+
+    /// State topic prefix, `{serial}` is replaced with inverter serial
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_topic_prefix: Option<String>,
+
+    /// Prefix of the bridge status topic
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt_bridge_prefix: Option<String>,
+
+    /// Seconds without data before inverter is marked offline
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inverter_offline_timeout: Option<u64>,
+
+    /// Seconds to finish connections and flush outputs on shutdown
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Append all bytes received from data loggers to this file
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_file: Option<String>,
+
+    /// File keeping the last known state of data loggers across restarts
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<String>,
+
+    /// Port of the HTTP server exposing metrics
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_port: Option<u16>,
+
+    /// InfluxDB v2 server URL, enables writing readings to InfluxDB
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influx_url: Option<String>,
+
+    /// InfluxDB organization
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influx_org: Option<String>,
+
+    /// InfluxDB bucket
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influx_bucket: Option<String>,
+
+    /// InfluxDB API token
+    #[arg(long, env, hide_env_values = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influx_token: Option<String>,
+
+    /// Maximum number of readings written to InfluxDB at once
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influx_batch_size: Option<usize>,
+
+    /// Seconds between InfluxDB writes
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influx_flush_interval: Option<u64>,
+
+    /// Modbus slave ID of the inverter
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modbus_slave_id: Option<u8>,
+
+    /// Cloud server `host:port` to forward data logger connections to
+    #[arg(long, env)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cloud_address: Option<String>,
+}
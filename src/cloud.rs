@@ -0,0 +1,33 @@
+use anyhow::Context;
+use bytes::Bytes;
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tracing::info;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens the upstream connection for a data logger in forwarding mode.
+pub async fn connect(address: &str) -> anyhow::Result<TcpStream> {
+    let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .with_context(|| format!("Timed out connecting to cloud server {address}"))?
+        .with_context(|| format!("Error connecting to cloud server {address}"))?;
+
+    info!("Forwarding connection to cloud server {address}");
+    Ok(stream)
+}
+
+/// Forwards frames from the data logger to the cloud server.
+pub async fn write_frames(
+    writer: &mut (impl AsyncWrite + Unpin),
+    frames: &[Bytes],
+) -> io::Result<()> {
+    for frame in frames {
+        writer.write_all(frame).await?;
+    }
+    Ok(())
+}
@@ -1,108 +1,273 @@
+use crate::messages::Data;
 use crate::messages::IncomingMessageData;
+use crate::messages::ModbusResponseData;
 use crate::messages::OutgoingMessageData;
 use crate::messages::SofarMessage;
 use crate::messages::SofarMessageType;
 use anyhow::anyhow;
 use bytes::Buf;
 use bytes::BufMut;
+use bytes::Bytes;
 use bytes::BytesMut;
 use num_traits::FromPrimitive;
-use std::io::Cursor;
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
-use tracing::debug;
-
+use tracing::{debug, warn};
+
+const START_MARKER: u8 = 0xa5;
+const END_MARKER: u8 = 0x15;
+const HEADER_LENGTH: usize = 1 + 2 + 2 + 1 + 1 + 4;
+const FOOTER_LENGTH: usize = 1 + 1;
+/// Upper bound for payload length, larger values come from misaligned input.
+const MAX_PAYLOAD_LENGTH: usize = 1024;
+
+/// Codec for the Solarman V5 frames exchanged with the data logger.
+///
+/// Decoder skips bytes preceding the start marker and drops frames with
+/// invalid length, end marker, checksum or payload, resynchronising on the
+/// next start marker.
 #[derive(Default)]
-pub struct SofarCodec;
+pub struct SofarCodec {
+    dropped_bytes: u64,
+    dropped_frames: u64,
+    frames: Vec<Bytes>,
+}
 
-impl Decoder for SofarCodec {
-    type Item = SofarMessage<IncomingMessageData>;
-    type Error = anyhow::Error;
+impl SofarCodec {
+    /// Number of bytes discarded while looking for a valid frame.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> anyhow::Result<Option<Self::Item>, Self::Error> {
-        debug!("Trying to decode data ({:#?})", buf);
-        let header_data_length: usize = 1 + 2 + 2 + 1 + 1 + 4;
-        let footer_data_length: usize = 1 + 1;
+    /// Number of candidate frames rejected as corrupt.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
 
-        if buf.capacity() < header_data_length {
-            buf.reserve(header_data_length - buf.capacity())
-        }
+    /// Complete frames read since the last call exactly as received, with
+    /// the frame of the message decoded last at the end.
+    ///
+    /// Frames that passed framing checks but could not be parsed are
+    /// included, bytes skipped while resynchronizing are not.
+    pub fn take_frames(&mut self) -> Vec<Bytes> {
+        std::mem::take(&mut self.frames)
+    }
 
-        if buf.len() < header_data_length {
-            debug!("Too little data to read header ({:?})", buf.len());
-            return Ok(None);
-        }
+    fn drop_bytes(&mut self, buf: &mut BytesMut, count: usize) {
+        buf.advance(count);
+        self.dropped_bytes += count as u64;
+    }
 
-        let mut peeker = Cursor::new(&buf[..header_data_length]);
+    fn drop_frame(&mut self, buf: &mut BytesMut, count: usize, reason: &str) {
+        warn!("Dropping frame ({reason})");
+        self.drop_bytes(buf, count);
+        self.dropped_frames += 1;
+    }
 
-        // swallow first byte
-        peeker.get_u8();
+    /// Parses a frame that passed length, end marker and checksum checks.
+    fn parse_frame(frame: &[u8]) -> anyhow::Result<SofarMessage<IncomingMessageData>> {
+        let mut header = &frame[1..HEADER_LENGTH];
+        let message_length = header.get_u16_le() as usize;
 
-        let message_length = peeker.get_u16_le() as usize;
+        let control_code = header.get_u16_le();
+        let message_type = SofarMessageType::from_u16(control_code);
+        debug!(
+            "Decoded message type: {:?} ({control_code:#06x})",
+            message_type
+        );
 
-        if buf.capacity() < header_data_length + message_length + footer_data_length {
-            buf.reserve(header_data_length + message_length + footer_data_length - buf.capacity())
-        }
+        let message_number = header.get_u8();
+        let message_number_2 = header.get_u8();
+        let data_logger_sn = header.get_u32_le();
 
-        if buf.len() < (header_data_length + message_length + footer_data_length) {
-            debug!("Waiting for more data ({:?})", buf.len());
-            return Ok(None);
-        }
+        // footer is kept in the slice, `Hello` layout is one byte longer than
+        // the declared payload length
+        let payload = &frame[HEADER_LENGTH..];
+
+        let data = match message_type {
+            Some(SofarMessageType::Heartbeat) => {
+                IncomingMessageData::Heartbeat(bincode::deserialize(payload)?)
+            }
+            Some(SofarMessageType::Data) => {
+                IncomingMessageData::Data(Box::new(Data::from_bytes(payload, message_length)?))
+            }
+            Some(SofarMessageType::Hello) => {
+                IncomingMessageData::Hello(bincode::deserialize(payload)?)
+            }
+            Some(SofarMessageType::HelloCd) => {
+                IncomingMessageData::HelloCd(bincode::deserialize(payload)?)
+            }
+            Some(SofarMessageType::Unknown44) => {
+                IncomingMessageData::Unknown44(bincode::deserialize(payload)?)
+            }
+            Some(SofarMessageType::ModbusResponse) => IncomingMessageData::ModbusResponse(
+                ModbusResponseData::from_bytes(&payload[..message_length])?,
+            ),
+            Some(SofarMessageType::ModbusRequest) => {
+                return Err(anyhow!("Unexpected message type {message_type:?}"))
+            }
+            None => IncomingMessageData::Unknown {
+                control_code,
+                payload: Bytes::copy_from_slice(&payload[..message_length]),
+            },
+        };
 
-        let calculated_checksum: u8 =
-            calc_checksum(&buf[1..header_data_length + message_length + footer_data_length - 2])
-                .unwrap_or(0);
+        debug!("Decoded payload: {:?}", data);
 
-        debug!("Calculating checksum: {:?}", calculated_checksum);
+        Ok(SofarMessage {
+            data,
+            control_code,
+            message_number,
+            message_number_2,
+            data_logger_sn,
+        })
+    }
+}
 
-        // swallow first byte
-        buf.get_u8();
-        // swallow message length
-        buf.get_u16_le();
+impl SofarCodec {
+    /// Skips to the next frame passing length, end marker and checksum
+    /// checks, returning its length once it is complete.
+    fn next_frame(&mut self, buf: &mut BytesMut) -> Option<usize> {
+        loop {
+            match buf.iter().position(|byte| *byte == START_MARKER) {
+                Some(0) => {}
+                Some(position) => {
+                    warn!("Skipping {position} bytes before start marker");
+                    self.drop_bytes(buf, position);
+                }
+                None => {
+                    if !buf.is_empty() {
+                        warn!("Skipping {} bytes without start marker", buf.len());
+                        let length = buf.len();
+                        self.drop_bytes(buf, length);
+                    }
+                    return None;
+                }
+            }
 
-        let message_type_bytes = buf.get_u16_le();
-        let message_type = SofarMessageType::from_u16(message_type_bytes)
-            .ok_or(anyhow!("Unknown message type {message_type_bytes}"))?;
-        debug!("Decoded message type: {:?}", message_type);
+            if buf.len() < HEADER_LENGTH {
+                debug!("Too little data to read header ({:?})", buf.len());
+                buf.reserve(HEADER_LENGTH - buf.len());
+                return None;
+            }
 
-        let message_number = buf.get_u8();
-        let message_number_2 = buf.get_u8();
-        let data_logger_sn = buf.get_u32_le();
+            let message_length = (&buf[1..3]).get_u16_le() as usize;
 
-        let data = match message_type {
-            SofarMessageType::Heartbeat => {
-                bincode::deserialize(buf).map(IncomingMessageData::Heartbeat)
+            if message_length > MAX_PAYLOAD_LENGTH {
+                self.drop_frame(buf, 1, &format!("payload length {message_length} too big"));
+                continue;
+            }
+
+            let frame_length = HEADER_LENGTH + message_length + FOOTER_LENGTH;
+
+            if buf.len() < frame_length {
+                debug!("Waiting for more data ({:?})", buf.len());
+                buf.reserve(frame_length - buf.len());
+                return None;
             }
-            SofarMessageType::Data => bincode::deserialize(buf).map(IncomingMessageData::Data),
-            SofarMessageType::Hello => bincode::deserialize(buf).map(IncomingMessageData::Hello),
-            SofarMessageType::HelloCd => {
-                bincode::deserialize(buf).map(IncomingMessageData::HelloCd)
+
+            let frame = &buf[..frame_length];
+
+            if frame[frame_length - 1] != END_MARKER {
+                self.drop_frame(buf, 1, "missing end marker");
+                continue;
             }
-            SofarMessageType::Unknown44 => {
-                bincode::deserialize(buf).map(IncomingMessageData::Unknown44)
+
+            let checksum = frame[frame_length - 2];
+            let calculated_checksum = calc_checksum(&frame[1..frame_length - 2]).unwrap_or(0);
+            debug!("Calculating checksum: {:?}", calculated_checksum);
+
+            if checksum != calculated_checksum {
+                self.drop_frame(buf, 1, &format!("invalid checksum {checksum}"));
+                continue;
             }
-        }?;
 
-        debug!("Decoded payload: {:?}", data);
+            return Some(frame_length);
+        }
+    }
 
-        buf.advance(message_length);
+    fn drop_remaining(&mut self, buf: &mut BytesMut) {
+        if !buf.is_empty() {
+            let length = buf.len();
+            self.drop_frame(buf, length, "truncated at end of stream");
+        }
+    }
+}
 
-        let checksum = buf.get_u8();
+impl Decoder for SofarCodec {
+    type Item = SofarMessage<IncomingMessageData>;
+    type Error = anyhow::Error;
 
-        if checksum != calculated_checksum {
-            return Err(anyhow!("Invalid checksum {checksum}"));
+    fn decode(&mut self, buf: &mut BytesMut) -> anyhow::Result<Option<Self::Item>, Self::Error> {
+        debug!("Trying to decode data ({:#?})", buf);
+
+        while let Some(frame_length) = self.next_frame(buf) {
+            // framing is valid at this point, so the whole frame is dropped on errors
+            match Self::parse_frame(&buf[..frame_length]) {
+                Ok(message) => {
+                    self.frames.push(buf.split_to(frame_length).freeze());
+                    return Ok(Some(message));
+                }
+                Err(err) => {
+                    self.frames
+                        .push(Bytes::copy_from_slice(&buf[..frame_length]));
+                    self.drop_frame(buf, frame_length, &err.to_string());
+                }
+            }
         }
 
-        // swallow last byte
-        buf.get_u8();
+        Ok(None)
+    }
 
-        Ok(Some(SofarMessage {
-            data,
-            message_type,
-            message_number,
-            message_number_2,
-            data_logger_sn,
-        }))
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> anyhow::Result<Option<Self::Item>> {
+        let message = self.decode(buf)?;
+
+        if message.is_none() {
+            self.drop_remaining(buf);
+        }
+
+        Ok(message)
+    }
+}
+
+/// Writes an already encoded frame as is, used when forwarding frames.
+impl Encoder<Bytes> for SofarCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Bytes, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        buf.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+/// Codec splitting a stream into whole frames without parsing them.
+///
+/// Framing is checked the same way as in [`SofarCodec`], so only complete
+/// frames are passed on.
+#[derive(Default)]
+pub struct RawFrameCodec {
+    inner: SofarCodec,
+}
+
+impl Decoder for RawFrameCodec {
+    type Item = Bytes;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self
+            .inner
+            .next_frame(buf)
+            .map(|frame_length| buf.split_to(frame_length).freeze()))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let frame = self.decode(buf)?;
+
+        if frame.is_none() {
+            self.inner.drop_remaining(buf);
+        }
+
+        Ok(frame)
     }
 }
 
@@ -116,20 +281,14 @@ impl Encoder<SofarMessage<OutgoingMessageData>> for SofarCodec {
     ) -> anyhow::Result<(), Self::Error> {
         debug!("Payload to encode: {:?}", item);
 
-        let response_type = match item.message_type {
-            SofarMessageType::Data => 0x1210,
-            SofarMessageType::Heartbeat => 0x1710,
-            SofarMessageType::Hello => 0x1110,
-            SofarMessageType::HelloCd => 0x1810,
-            SofarMessageType::Unknown44 => 0x1310,
-        };
         let data = match item.data {
-            OutgoingMessageData::ServerResponse(data) => bincode::serialize(&data),
-        }?;
+            OutgoingMessageData::ServerResponse(data) => bincode::serialize(&data)?,
+            OutgoingMessageData::ModbusRequest(data) => data.to_bytes(),
+        };
 
         buf.put_u8(0xa5);
         buf.put_u16_le(u16::try_from(data.len()).unwrap());
-        buf.put_u16_le(response_type);
+        buf.put_u16_le(item.control_code);
         buf.put_u8(item.message_number);
         buf.put_u8(item.message_number_2);
         buf.put_u32_le(item.data_logger_sn);
@@ -147,14 +306,19 @@ fn calc_checksum(buf: &[u8]) -> Option<u8> {
     buf.iter().copied().reduce(|a, b| a.wrapping_add(b))
 }
 
+/// Sample frames, also fed to the connection handler and API in their tests.
+#[cfg(test)]
+pub(crate) use tests::{DATA, HEARTBEAT};
+
 #[cfg(test)]
 mod tests {
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
     use tokio_util::codec::{Decoder, Encoder};
 
     use crate::{
         codec::SofarCodec,
         messages::{IncomingMessageData, SofarMessage},
+        modbus::{ModbusRequest, ModbusResponse},
     };
 
     #[test]
@@ -260,4 +424,169 @@ mod tests {
 
         assert_eq!(response_bytes, expected_response_bytes);
     }
+
+    #[test]
+    fn modbus_request_message() {
+        let expected_request_bytes = BytesMut::from_iter(vec![
+            165, 23, 0, 16, 69, 1, 0, 79, 172, 254, 103, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 1, 3, 0, 0, 0, 1, 132, 10, 98, 21,
+        ]);
+
+        let mut codec = SofarCodec::default();
+        let mut request_bytes = BytesMut::new();
+        let request = ModbusRequest::read_holding_registers(1, 0x0000, 1);
+        let request_message = SofarMessage::modbus_request(1744743503, 1, &request);
+        codec.encode(request_message, &mut request_bytes).unwrap();
+
+        assert_eq!(request_bytes, expected_request_bytes);
+    }
+
+    #[test]
+    fn modbus_response_message() {
+        let mut message_bytes = BytesMut::from_iter(vec![
+            165, 21, 0, 16, 21, 1, 0, 79, 172, 254, 103, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            1, 3, 2, 0, 42, 57, 155, 162, 21,
+        ]);
+
+        let mut codec = SofarCodec::default();
+        let message = codec.decode(&mut message_bytes).unwrap().unwrap();
+
+        let IncomingMessageData::ModbusResponse(data) = message.data else {
+            panic!("Expected Modbus response, got {:?}", message.data);
+        };
+        assert_eq!(message.data_logger_sn, 1744743503);
+        assert_eq!(
+            data.response().unwrap(),
+            ModbusResponse::Registers(vec![42])
+        );
+    }
+
+    pub(crate) const HEARTBEAT: [u8; 14] =
+        [165, 1, 0, 16, 71, 31, 32, 79, 172, 254, 103, 0, 247, 21];
+    pub(crate) const DATA: [u8; 164] = [
+        165, 151, 0, 16, 66, 4, 5, 79, 172, 254, 103, 1, 1, 39, 72, 125, 14, 0, 128, 0, 0, 0, 69,
+        170, 88, 100, 1, 0, 40, 13, 0, 0, 83, 70, 52, 69, 83, 48, 48, 51, 77, 52, 67, 48, 53, 56,
+        32, 32, 104, 1, 122, 11, 213, 2, 12, 0, 0, 0, 9, 0, 10, 0, 9, 0, 195, 8, 216, 8, 201, 8,
+        135, 19, 54, 1, 0, 0, 69, 0, 0, 0, 174, 126, 0, 0, 220, 24, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 86, 50, 56, 48, 86, 49, 48, 48, 21, 0, 4, 24, 100, 11, 193, 2, 60,
+        0, 1, 0, 40, 5, 87, 6, 33, 5, 7, 0, 0, 0, 0, 0, 6, 0, 226, 3, 227, 3, 227, 3, 86, 50, 56,
+        48, 86, 50, 56, 48, 23, 5, 19, 9, 36, 49, 37, 0, 0, 0, 96, 21,
+    ];
+
+    #[test]
+    fn noisy_stream() {
+        let mut corrupted_heartbeat = HEARTBEAT;
+        corrupted_heartbeat[12] = 0;
+        let mut message_bytes = BytesMut::new();
+        message_bytes.extend_from_slice(&[0, 1, 165, 7]);
+        message_bytes.extend_from_slice(&HEARTBEAT);
+        message_bytes.extend_from_slice(&corrupted_heartbeat);
+        message_bytes.extend_from_slice(&[21, 255]);
+        message_bytes.extend_from_slice(&DATA);
+
+        let mut codec = SofarCodec::default();
+        let heartbeat = codec.decode(&mut message_bytes).unwrap().unwrap();
+        let data = codec.decode(&mut message_bytes).unwrap().unwrap();
+
+        assert!(matches!(heartbeat.data, IncomingMessageData::Heartbeat(_)));
+        assert!(matches!(data.data, IncomingMessageData::Data(_)));
+        assert!(message_bytes.is_empty());
+        assert_eq!(codec.dropped_frames(), 2);
+        assert_eq!(codec.dropped_bytes(), 4 + 14 + 2);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut message_bytes = BytesMut::new();
+        message_bytes.extend_from_slice(&DATA[..20]);
+        message_bytes.extend_from_slice(&HEARTBEAT);
+
+        let mut codec = SofarCodec::default();
+        // truncated frame claims more bytes than received so far
+        assert!(codec.decode(&mut message_bytes).unwrap().is_none());
+
+        message_bytes.extend_from_slice(&DATA);
+        let heartbeat = codec.decode(&mut message_bytes).unwrap().unwrap();
+        let data = codec.decode(&mut message_bytes).unwrap().unwrap();
+
+        assert!(matches!(heartbeat.data, IncomingMessageData::Heartbeat(_)));
+        assert!(matches!(data.data, IncomingMessageData::Data(_)));
+        assert_eq!(codec.dropped_frames(), 1);
+        assert_eq!(codec.dropped_bytes(), 20);
+    }
+
+    #[test]
+    fn interleaved_frames() {
+        let mut message_bytes = BytesMut::new();
+        message_bytes.extend_from_slice(&DATA[..50]);
+        message_bytes.extend_from_slice(&HEARTBEAT);
+        message_bytes.extend_from_slice(&DATA[50..]);
+        message_bytes.extend_from_slice(&DATA);
+
+        let mut codec = SofarCodec::default();
+        let heartbeat = codec.decode(&mut message_bytes).unwrap().unwrap();
+        let data = codec.decode(&mut message_bytes).unwrap().unwrap();
+
+        assert!(matches!(heartbeat.data, IncomingMessageData::Heartbeat(_)));
+        assert!(matches!(data.data, IncomingMessageData::Data(_)));
+        assert!(message_bytes.is_empty());
+        assert_eq!(codec.dropped_bytes(), 164);
+    }
+
+    #[test]
+    fn truncated_frame_at_end_of_stream() {
+        let mut message_bytes = BytesMut::new();
+        message_bytes.extend_from_slice(&HEARTBEAT);
+        message_bytes.extend_from_slice(&DATA[..20]);
+
+        let mut codec = SofarCodec::default();
+        assert!(codec.decode_eof(&mut message_bytes).unwrap().is_some());
+        assert!(codec.decode_eof(&mut message_bytes).unwrap().is_none());
+        assert!(message_bytes.is_empty());
+        assert_eq!(codec.dropped_frames(), 1);
+    }
+
+    #[test]
+    fn unknown_message() {
+        let mut message_bytes = BytesMut::from_iter(vec![
+            165, 3, 0, 16, 73, 255, 0, 79, 172, 254, 103, 1, 2, 3, 193, 21,
+        ]);
+        let expected_response_bytes = BytesMut::from_iter(vec![
+            165, 10, 0, 16, 25, 0, 0, 79, 172, 254, 103, 1, 1, 141, 39, 103, 100, 120, 0, 0, 0,
+            140, 21,
+        ]);
+
+        let mut codec = SofarCodec::default();
+        let message = codec.decode(&mut message_bytes).unwrap().unwrap();
+
+        assert!(message.message_type().is_none());
+        assert!(matches!(
+            &message.data,
+            IncomingMessageData::Unknown {
+                control_code: 0x4910,
+                payload,
+            } if *payload == Bytes::from_static(&[1, 2, 3])
+        ));
+
+        let mut response_bytes = BytesMut::new();
+        let response_message = SofarMessage::from_incoming_message(&message, 1684481933);
+        codec.encode(response_message, &mut response_bytes).unwrap();
+
+        assert_eq!(response_bytes, expected_response_bytes);
+    }
+
+    #[test]
+    fn grid_tied_data_has_no_hybrid_block() {
+        let mut codec = SofarCodec::default();
+        let message = codec
+            .decode(&mut BytesMut::from(&DATA[..]))
+            .unwrap()
+            .unwrap();
+
+        let IncomingMessageData::Data(data) = message.data else {
+            panic!("Expected data, got {:?}", message.data);
+        };
+        assert!(data.hybrid.is_none());
+        assert!(serde_json::to_value(&data).unwrap().get("hybrid").is_none());
+    }
 }
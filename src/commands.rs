@@ -0,0 +1,271 @@
+use crate::{homeassistant::EntityType, modbus::ModbusRequest};
+use anyhow::anyhow;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Inverter settings writable through `<prefix>/set/<setting>` topics.
+///
+/// Register addresses follow the Sofar G3 Modbus protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// Remote on/off switch, 1 turns the inverter on
+    Power,
+    /// Export limitation switch, 1 enables the limit
+    ExportLimitation,
+    /// Active power output limit in percent of nominal power
+    ActivePowerLimit,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 3] = [
+        Setting::Power,
+        Setting::ExportLimitation,
+        Setting::ActivePowerLimit,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::Power => "power",
+            Setting::ExportLimitation => "export_limitation",
+            Setting::ActivePowerLimit => "active_power_limit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Setting::ALL
+            .into_iter()
+            .find(|setting| setting.name() == name)
+    }
+
+    pub fn register(&self) -> u16 {
+        match self {
+            Setting::Power => 0x1104,
+            Setting::ExportLimitation => 0x1105,
+            Setting::ActivePowerLimit => 0x110a,
+        }
+    }
+
+    pub fn from_register(register: u16) -> Option<Self> {
+        Setting::ALL
+            .into_iter()
+            .find(|setting| setting.register() == register)
+    }
+
+    /// Converts command payload into the register value.
+    pub fn parse(&self, payload: &str) -> anyhow::Result<u16> {
+        let payload = payload.trim();
+
+        match self {
+            Setting::Power | Setting::ExportLimitation => match payload {
+                "ON" => Ok(1),
+                "OFF" => Ok(0),
+                _ => Err(anyhow!("Expected ON or OFF, got {payload:?}")),
+            },
+            Setting::ActivePowerLimit => {
+                let value = payload.parse::<f32>()?.round();
+                if !(0.0..=100.0).contains(&value) {
+                    return Err(anyhow!("Power limit {value} out of range 0-100"));
+                }
+                Ok(value as u16)
+            }
+        }
+    }
+
+    pub fn entity(&self, value: u16) -> EntityType {
+        let name = self.name().to_string();
+
+        match self {
+            Setting::Power | Setting::ExportLimitation => EntityType::Switch {
+                name,
+                value: value != 0,
+            },
+            Setting::ActivePowerLimit => EntityType::Number {
+                name,
+                value: f32::from(value),
+                min: 0.0,
+                max: 100.0,
+                unit: String::from("%"),
+            },
+        }
+    }
+
+    pub fn read_request(&self, slave_id: u8) -> ModbusRequest {
+        ModbusRequest::read_holding_registers(slave_id, self.register(), 1)
+    }
+
+    pub fn write_request(&self, slave_id: u8, value: u16) -> ModbusRequest {
+        ModbusRequest::write_single_register(slave_id, self.register(), value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Command {
+    pub setting: Setting,
+    pub value: u16,
+}
+
+/// Sender of a Modbus request answered by the data logger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requester {
+    /// The bridge, with the setting read, if any
+    Bridge(Option<Setting>),
+    /// The cloud server, in forwarding mode
+    Cloud,
+    Unknown,
+}
+
+/// Modbus requests of a connection waiting for responses, by V5 sequence
+/// number.
+///
+/// In forwarding mode the cloud server sends its own requests over the same
+/// connection, so the sender of every request is tracked and the bridge skips
+/// sequence numbers the cloud server is waiting on.
+#[derive(Default)]
+pub struct PendingRequests {
+    sequence: u8,
+    bridge: HashMap<u8, Option<Setting>>,
+    cloud: HashSet<u8>,
+}
+
+impl PendingRequests {
+    /// Returns the sequence number for a request of the bridge, reading
+    /// `setting` if given.
+    pub fn bridge_request(&mut self, setting: Option<Setting>) -> u8 {
+        for _ in 0..=u8::MAX {
+            self.sequence = self.sequence.wrapping_add(1);
+
+            if !self.cloud.contains(&self.sequence) {
+                break;
+            }
+        }
+
+        self.bridge.insert(self.sequence, setting);
+        self.sequence
+    }
+
+    /// Records a request the cloud server sent to the data logger.
+    pub fn cloud_request(&mut self, sequence: u8) {
+        if self.bridge.remove(&sequence).is_some() {
+            warn!("Cloud server reused sequence number {sequence}, dropping own request");
+        }
+        self.cloud.insert(sequence);
+    }
+
+    /// Returns who sent the request answered by the response.
+    pub fn response(&mut self, sequence: u8) -> Requester {
+        if let Some(setting) = self.bridge.remove(&sequence) {
+            Requester::Bridge(setting)
+        } else if self.cloud.remove(&sequence) {
+            Requester::Cloud
+        } else {
+            Requester::Unknown
+        }
+    }
+}
+
+/// Routes commands to connections handling given inverter.
+///
+/// Connections register under the inverter serial number, so the router does
+/// not depend on how commands are received.
+#[derive(Clone, Default)]
+pub struct CommandRouter {
+    connections: Arc<Mutex<HashMap<String, mpsc::Sender<Command>>>>,
+}
+
+impl CommandRouter {
+    pub fn register(&self, serial: &str, sender: mpsc::Sender<Command>) {
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(serial.to_owned(), sender);
+    }
+
+    pub fn unregister(&self, serial: &str, sender: &mpsc::Sender<Command>) {
+        let mut connections = self.connections.lock().unwrap();
+
+        // only remove own registration, newer connection might have replaced it
+        if connections
+            .get(serial)
+            .is_some_and(|registered| registered.same_channel(sender))
+        {
+            connections.remove(serial);
+        }
+    }
+
+    /// Hands command setting `name` to `payload` to the connection.
+    pub fn route(&self, serial: &str, name: &str, payload: &str) {
+        let Some(setting) = Setting::from_name(name) else {
+            warn!("Unknown setting {name:?} for inverter {serial}");
+            return;
+        };
+        let value = match setting.parse(payload) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Invalid payload for {name} of inverter {serial} ({err})");
+                return;
+            }
+        };
+
+        match self.connections.lock().unwrap().get(serial) {
+            Some(sender) => {
+                if let Err(err) = sender.try_send(Command { setting, value }) {
+                    warn!("Dropping command {name} for inverter {serial} ({err})");
+                }
+            }
+            None => warn!("No logger connected for inverter {serial}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::{Command, CommandRouter, PendingRequests, Requester, Setting};
+
+    #[test]
+    fn routes_command_to_connection() {
+        let router = CommandRouter::default();
+        let (sender, mut receiver) = mpsc::channel(1);
+        router.register("sf4es003m4c058", sender);
+
+        router.route("sf4es003m4c058", "active_power_limit", "42.4");
+
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            Command {
+                setting: Setting::ActivePowerLimit,
+                value: 42
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_payload() {
+        let router = CommandRouter::default();
+        let (sender, mut receiver) = mpsc::channel(1);
+        router.register("sf4es003m4c058", sender);
+
+        router.route("sf4es003m4c058", "power", "1");
+        router.route("sf4es003m4c058", "active_power_limit", "120");
+
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn tracks_requester_of_responses() {
+        let mut pending = PendingRequests::default();
+
+        pending.cloud_request(1);
+        let sequence = pending.bridge_request(Some(Setting::Power));
+        assert_eq!(sequence, 2);
+
+        assert_eq!(pending.response(1), Requester::Cloud);
+        assert_eq!(pending.response(2), Requester::Bridge(Some(Setting::Power)));
+        assert_eq!(pending.response(2), Requester::Unknown);
+    }
+}
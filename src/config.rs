@@ -1,8 +1,15 @@
-use serde::Deserialize;
+use crate::{cli::Cli, homeassistant::EntityType, messages::Phases};
+use anyhow::{ensure, Context};
+use figment::{
+    providers::{Format, Serialized, Toml, Yaml},
+    Figment,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ffi::OsStr, path::Path};
 
 pub static MQTT_CLIENT_ID: &str = "sofar-mqtt";
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
     #[serde(default = "default_mqtt_host")]
     pub mqtt_host: String,
@@ -10,8 +17,229 @@ pub struct Config {
     pub mqtt_port: u16,
     pub mqtt_user: Option<String>,
     pub mqtt_password: Option<String>,
+    #[serde(default)]
+    pub mqtt_tls: bool,
+    pub mqtt_ca_file: Option<String>,
+    pub mqtt_client_cert_file: Option<String>,
+    pub mqtt_client_key_file: Option<String>,
+    #[serde(default)]
+    pub mqtt_tls_insecure: bool,
     #[serde(default = "default_tcp_port")]
     pub tcp_port: u16,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub mqtt_discovery_prefix: String,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub mqtt_topic_prefix: String,
+    #[serde(default = "default_mqtt_bridge_prefix")]
+    pub mqtt_bridge_prefix: String,
+    #[serde(default = "default_inverter_offline_timeout")]
+    pub inverter_offline_timeout: u64,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default = "default_modbus_slave_id")]
+    pub modbus_slave_id: u8,
+    pub capture_file: Option<String>,
+    /// State is kept in memory only when not set
+    pub state_file: Option<String>,
+    /// Port of the HTTP server exposing metrics, disabled when not set
+    pub http_port: Option<u16>,
+    /// InfluxDB v2 server, readings are written when set
+    pub influx_url: Option<String>,
+    pub influx_org: Option<String>,
+    pub influx_bucket: Option<String>,
+    pub influx_token: Option<String>,
+    #[serde(default = "default_influx_batch_size")]
+    pub influx_batch_size: usize,
+    #[serde(default = "default_influx_flush_interval")]
+    pub influx_flush_interval: u64,
+    /// Solarman cloud server, data logger connections are forwarded when set
+    pub cloud_address: Option<String>,
+    /// Per-inverter overrides keyed by inverter or data logger serial number,
+    /// only available in the configuration file.
+    #[serde(default)]
+    pub inverters: HashMap<String, InverterConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InverterConfig {
+    /// Device name shown in Home Assistant
+    pub name: Option<String>,
+    /// Home Assistant area suggested for the device
+    pub area: Option<String>,
+    pub model: Option<String>,
+    /// Grid phases, detected from the data frames when not set
+    pub phases: Option<Phases>,
+    /// Entities to publish, all entities are published when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_entities: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_entities: Vec<String>,
+    /// Correction factors multiplying the values of given entities
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub corrections: HashMap<String, f64>,
+}
+
+impl InverterConfig {
+    /// Drops suppressed entity or applies its correction factor.
+    pub fn apply(&self, mut entity: EntityType) -> Option<EntityType> {
+        let name = entity.name();
+
+        if (!self.include_entities.is_empty() && !self.include_entities.iter().any(|n| n == name))
+            || self.exclude_entities.iter().any(|n| n == name)
+        {
+            return None;
+        }
+
+        if let Some(factor) = self.corrections.get(name) {
+            entity.scale(*factor);
+        }
+
+        Some(entity)
+    }
+}
+
+impl Config {
+    /// Merges configuration file, environment variables and command line
+    /// options, in order of increasing precedence.
+    ///
+    /// Environment variables are resolved by `clap` together with the
+    /// matching command line options.
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let mut figment = Figment::new();
+
+        if let Some(path) = &cli.config {
+            figment = match path.extension().and_then(OsStr::to_str) {
+                Some("yaml" | "yml") => figment.merge(Yaml::file_exact(path)),
+                _ => figment.merge(Toml::file_exact(path)),
+            };
+        }
+
+        let config: Config = figment
+            .merge(Serialized::defaults(&cli.overrides))
+            .extract()
+            .context("Invalid configuration")?;
+
+        config.validate().context("Invalid configuration")?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(self.mqtt_port != 0, "mqtt_port must not be 0");
+        ensure!(self.tcp_port != 0, "tcp_port must not be 0");
+        ensure!(self.http_port != Some(0), "http_port must not be 0");
+        ensure!(
+            self.mqtt_topic_prefix.contains("{serial}"),
+            "mqtt_topic_prefix must contain {{serial}} placeholder"
+        );
+
+        for (name, prefix) in [
+            ("mqtt_discovery_prefix", &self.mqtt_discovery_prefix),
+            ("mqtt_topic_prefix", &self.mqtt_topic_prefix),
+            ("mqtt_bridge_prefix", &self.mqtt_bridge_prefix),
+        ] {
+            ensure!(
+                !prefix.is_empty() && !prefix.contains(['+', '#']),
+                "{name} must not be empty or contain MQTT wildcards"
+            );
+        }
+
+        ensure!(
+            self.mqtt_client_cert_file.is_some() == self.mqtt_client_key_file.is_some(),
+            "mqtt_client_cert_file and mqtt_client_key_file have to be set together"
+        );
+
+        for file in [
+            &self.mqtt_ca_file,
+            &self.mqtt_client_cert_file,
+            &self.mqtt_client_key_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            ensure!(Path::new(file).is_file(), "{file} does not exist");
+        }
+
+        ensure!(
+            self.inverter_offline_timeout > 0,
+            "inverter_offline_timeout must be greater than 0"
+        );
+        ensure!(
+            self.shutdown_timeout > 0,
+            "shutdown_timeout must be greater than 0"
+        );
+        ensure!(
+            (1..=247).contains(&self.modbus_slave_id),
+            "modbus_slave_id must be between 1 and 247"
+        );
+
+        if let Some(address) = &self.cloud_address {
+            ensure!(
+                address
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()),
+                "cloud_address must be in host:port format"
+            );
+        }
+
+        if let Some(url) = &self.influx_url {
+            ensure!(
+                url.starts_with("http://") || url.starts_with("https://"),
+                "influx_url must be a http:// or https:// URL"
+            );
+            ensure!(
+                self.influx_org.is_some() && self.influx_bucket.is_some(),
+                "influx_org and influx_bucket have to be set together with influx_url"
+            );
+        }
+        ensure!(
+            self.influx_batch_size > 0,
+            "influx_batch_size must be greater than 0"
+        );
+        ensure!(
+            self.influx_flush_interval > 0,
+            "influx_flush_interval must be greater than 0"
+        );
+
+        for (key, inverter) in &self.inverters {
+            for (name, factor) in &inverter.corrections {
+                ensure!(
+                    factor.is_finite() && *factor > 0.0,
+                    "Correction factor of {name} for inverter {key} must be a positive number"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overrides for the inverter, matched by its serial number first and
+    /// data logger serial number second.
+    pub fn inverter(&self, inverter_serial: &str, data_logger_sn: u32) -> InverterConfig {
+        let inverter_serial = inverter_serial.trim();
+
+        self.inverters
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(inverter_serial))
+            .or_else(|| {
+                self.inverters
+                    .iter()
+                    .find(|(key, _)| **key == data_logger_sn.to_string())
+            })
+            .map(|(_, inverter)| inverter.clone())
+            .unwrap_or_default()
+    }
+
+    /// Copy of the configuration that is safe to print.
+    pub fn redacted(&self) -> Self {
+        Config {
+            mqtt_password: self
+                .mqtt_password
+                .as_ref()
+                .map(|_| String::from("********")),
+            influx_token: self.influx_token.as_ref().map(|_| String::from("********")),
+            ..self.clone()
+        }
+    }
 }
 
 fn default_tcp_port() -> u16 {
@@ -25,3 +253,126 @@ fn default_mqtt_host() -> String {
 fn default_mqtt_port() -> u16 {
     1883
 }
+
+fn default_mqtt_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    String::from("sofar_{serial}")
+}
+
+fn default_mqtt_bridge_prefix() -> String {
+    String::from("sofar_mqtt")
+}
+
+fn default_inverter_offline_timeout() -> u64 {
+    600
+}
+
+fn default_shutdown_timeout() -> u64 {
+    // below the 10 seconds docker stop waits before killing the container
+    8
+}
+
+fn default_modbus_slave_id() -> u8 {
+    1
+}
+
+fn default_influx_batch_size() -> usize {
+    500
+}
+
+fn default_influx_flush_interval() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+
+    use crate::{cli::Cli, config::Config, homeassistant::EntityType};
+
+    #[test]
+    fn command_line_overrides_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "mqtt_host = \"broker\"\nmqtt_port = 8883\n").unwrap();
+
+        let cli = Cli::parse_from([
+            "sofar-mqtt",
+            "--config",
+            path.to_str().unwrap(),
+            "--mqtt-port",
+            "1884",
+        ]);
+        let config = Config::load(&cli).unwrap();
+
+        assert_eq!(config.mqtt_host, "broker");
+        assert_eq!(config.mqtt_port, 1884);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(&path, "mqtt_topic_prefix: solar\n").unwrap();
+
+        let cli = Cli::parse_from(["sofar-mqtt", "--config", path.to_str().unwrap()]);
+        let err = Config::load(&cli).unwrap_err();
+
+        assert!(format!("{err:#}").contains("{serial}"));
+
+        fs::write(&path, "shutdown_timeout: 0\n").unwrap();
+        let err = Config::load(&cli).unwrap_err();
+
+        assert!(format!("{err:#}").contains("shutdown_timeout"));
+    }
+
+    #[test]
+    fn inverter_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            [inverters.SF4ES003M4C058]
+            name = "Garage"
+            exclude_entities = ["logger_temperature"]
+            corrections = { current_power = 1.1 }
+
+            [inverters.1744743503]
+            name = "Roof"
+            "#,
+        )
+        .unwrap();
+
+        let cli = Cli::parse_from(["sofar-mqtt", "--config", path.to_str().unwrap()]);
+        let config = Config::load(&cli).unwrap();
+
+        let garage = config.inverter("sf4es003m4c058 ", 1744743503);
+        assert_eq!(garage.name.as_deref(), Some("Garage"));
+        assert!(garage
+            .apply(EntityType::TemperatureSensor {
+                name: "logger_temperature".to_string(),
+                value: 30.0,
+            })
+            .is_none());
+        assert_eq!(
+            garage
+                .apply(EntityType::PowerSensor {
+                    name: "current_power".to_string(),
+                    value: 1000,
+                })
+                .unwrap()
+                .state(),
+            "1100"
+        );
+
+        let roof = config.inverter("SA1ES003M4C001", 1744743503);
+        assert_eq!(roof.name.as_deref(), Some("Roof"));
+        assert!(config.inverter("SA1ES003M4C001", 1).name.is_none());
+    }
+}
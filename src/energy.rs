@@ -0,0 +1,164 @@
+use crate::homeassistant::EntityType;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Smallest drop of the daily counter treated as a reset, in kWh.
+///
+/// The counter has a resolution of 0.01 kWh, smaller differences come from
+/// rounding.
+const RESET_THRESHOLD: f64 = 0.005;
+
+/// Running energy totals of a single inverter, computed from the daily
+/// counter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnergyCounters {
+    /// Inverter date the daily counter belongs to, year without century
+    date: (u8, u8, u8),
+    /// Latest value of the daily counter in kWh
+    daily_energy: f64,
+    /// Energy of finished days of the current month in kWh
+    month_energy: f64,
+    /// Energy of finished days of the current year in kWh
+    year_energy: f64,
+    /// Later inverter date seen before the counter was reset, with the
+    /// counter value at that point in kWh
+    #[serde(default)]
+    pending: Option<((u8, u8, u8), f64)>,
+}
+
+impl EnergyCounters {
+    pub fn new(date: (u8, u8, u8), daily_energy: f64) -> Self {
+        EnergyCounters {
+            date,
+            daily_energy,
+            month_energy: 0.0,
+            year_energy: 0.0,
+            pending: None,
+        }
+    }
+
+    /// Applies a reading of the daily counter taken on the inverter `date`.
+    ///
+    /// A day is closed once the daily counter drops, so clock drift past
+    /// midnight does not split a day. Until then, a later date only leaves
+    /// the value reached so far pending, as the counter may still hold the
+    /// energy of the previous day. If that date passes without a reset too,
+    /// the reset was missed and the pending value closes the day. Frames
+    /// dated before the latest date are stale values republished before the
+    /// reset and are ignored.
+    pub fn update(&mut self, date: (u8, u8, u8), daily_energy: f64) {
+        let latest = self.pending.map_or(self.date, |(date, _)| date);
+        if date < latest {
+            warn!("Ignoring daily energy {daily_energy} from {date:?}, before {latest:?}");
+            return;
+        }
+
+        if date > latest {
+            if let Some((pending_date, pending_energy)) = self.pending {
+                self.close_day(pending_energy, pending_date);
+            }
+            self.pending = Some((date, self.daily_energy));
+        }
+
+        if daily_energy + RESET_THRESHOLD < self.daily_energy {
+            let date = self.pending.take().map_or(date, |(date, _)| date);
+            self.close_day(self.daily_energy, date);
+        }
+
+        self.daily_energy = daily_energy;
+    }
+
+    /// Adds `energy` of the finished day to the totals and starts `date`.
+    fn close_day(&mut self, energy: f64, date: (u8, u8, u8)) {
+        let (year, month, _) = date;
+
+        self.month_energy = if (year, month) == (self.date.0, self.date.1) {
+            self.month_energy + energy
+        } else {
+            0.0
+        };
+        self.year_energy = if year == self.date.0 {
+            self.year_energy + energy
+        } else {
+            0.0
+        };
+        self.date = date;
+    }
+
+    pub fn entities(&self) -> Vec<EntityType> {
+        vec![
+            EntityType::EnergySensor {
+                name: "daily_energy".to_string(),
+                value: self.daily_energy,
+            },
+            EntityType::EnergySensor {
+                name: "monthly_energy".to_string(),
+                value: self.month_energy + self.daily_energy,
+            },
+            EntityType::EnergySensor {
+                name: "yearly_energy".to_string(),
+                value: self.year_energy + self.daily_energy,
+            },
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnergyCounters;
+
+    #[test]
+    fn tracks_resets_and_rollovers() {
+        let mut counters = EnergyCounters::new((23, 5, 30), 1.5);
+
+        counters.update((23, 5, 30), 12.25);
+        // stale value of yesterday's counter, then the reset at dawn
+        counters.update((23, 5, 31), 0.1);
+        counters.update((23, 5, 30), 12.25);
+        assert_eq!(counters.daily_energy, 0.1);
+        counters.update((23, 5, 31), 8.0);
+        assert_eq!(
+            (counters.month_energy, counters.year_energy),
+            (12.25, 12.25)
+        );
+
+        // clock drifted past midnight before the counter was reset
+        counters.update((23, 6, 1), 8.5);
+        assert_eq!(counters.date, (23, 5, 31));
+        counters.update((23, 6, 1), 0.2);
+        assert_eq!((counters.month_energy, counters.year_energy), (0.0, 20.75));
+
+        counters.update((24, 1, 1), 0.0);
+        assert_eq!((counters.month_energy, counters.year_energy), (0.0, 0.0));
+    }
+
+    #[test]
+    fn counts_days_once_when_reset_follows_new_date() {
+        let mut counters = EnergyCounters::new((23, 5, 30), 12.0);
+
+        // first frame of the day still holds yesterday's counter
+        counters.update((23, 5, 31), 12.0);
+        assert_eq!((counters.month_energy, counters.year_energy), (0.0, 0.0));
+        counters.update((23, 5, 31), 0.1);
+        assert_eq!(counters.date, (23, 5, 31));
+        assert_eq!((counters.month_energy, counters.year_energy), (12.0, 12.0));
+
+        counters.update((23, 5, 31), 3.0);
+        assert_eq!((counters.month_energy, counters.year_energy), (12.0, 12.0));
+    }
+
+    #[test]
+    fn closes_days_without_seen_reset() {
+        let mut counters = EnergyCounters::new((23, 5, 30), 12.0);
+
+        // the bridge was offline when the counter was reset, the day is only
+        // known to be finished once the next one starts
+        counters.update((23, 5, 31), 13.5);
+        counters.update((23, 5, 31), 20.0);
+        assert_eq!((counters.month_energy, counters.year_energy), (0.0, 0.0));
+
+        counters.update((23, 6, 1), 0.1);
+        assert_eq!(counters.date, (23, 6, 1));
+        assert_eq!((counters.month_energy, counters.year_energy), (0.0, 32.0));
+    }
+}
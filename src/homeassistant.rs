@@ -1,4 +1,7 @@
-use crate::messages::Data;
+use crate::{
+    messages::{Data, Hello, HybridData, Phases},
+    status::{active_faults, InverterStatus},
+};
 
 #[derive(serde::Serialize, Clone)]
 pub struct Device {
@@ -8,6 +11,14 @@ pub struct Device {
     pub model: String,
     pub name: String,
     pub sw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
+    /// Identifier of the device this one communicates through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<String>,
+    /// Connection type and value pairs, e.g. `("mac", "34:ea:e7:2c:3c:16")`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<(String, String)>,
 }
 
 #[derive(serde::Serialize, Debug)]
@@ -25,6 +36,9 @@ pub struct Attributes {
     pub hour: u8,
     pub minute: u8,
     pub second: u8,
+    pub active_faults: Vec<String>,
+    pub alert_message_code: u16,
+    pub inner_board_message_code: u16,
 }
 
 impl Attributes {
@@ -43,6 +57,9 @@ impl Attributes {
             timestamp: data.timestamp,
             total_time: data.total_time,
             year: data.year,
+            active_faults: active_faults(&data.fault_codes()),
+            alert_message_code: data.alert_message_code,
+            inner_board_message_code: data.inner_board_message_code,
         }
     }
 }
@@ -53,12 +70,31 @@ pub struct Entity {
     pub unique_id: String,
     pub object_id: String,
     pub qos: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub unit_of_measurement: Option<String>,
     pub state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub state_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f32>,
     pub device: Device,
     pub json_attributes_topic: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<Availability>,
+    pub availability_mode: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct Availability {
+    pub topic: String,
 }
 
 #[derive(Debug)]
@@ -68,6 +104,11 @@ pub enum EntityType {
         name: String,
         value: u32,
     },
+    /// Product of voltage and current, in VA
+    ApparentPowerSensor {
+        name: String,
+        value: u32,
+    },
     TemperatureSensor {
         name: String,
         value: f32,
@@ -76,6 +117,45 @@ pub enum EntityType {
         name: String,
         value: f64,
     },
+    VoltageSensor {
+        name: String,
+        value: f32,
+    },
+    CurrentSensor {
+        name: String,
+        value: f32,
+    },
+    FrequencySensor {
+        name: String,
+        value: f32,
+    },
+    ResistanceSensor {
+        name: String,
+        value: u16,
+    },
+    DurationSensor {
+        name: String,
+        value: u32,
+    },
+    /// Running time counter, only growing over the inverter lifetime
+    TotalDurationSensor {
+        name: String,
+        value: u32,
+    },
+    /// Battery state of charge in percent
+    BatterySensor {
+        name: String,
+        value: u16,
+    },
+    PercentageSensor {
+        name: String,
+        value: u16,
+    },
+    /// Point in time as seconds since epoch
+    TimestampSensor {
+        name: String,
+        value: u64,
+    },
     #[allow(dead_code)]
     GenericSensor {
         name: String,
@@ -85,76 +165,431 @@ pub enum EntityType {
         name: String,
         value: String,
     },
+    EnumSensor {
+        name: String,
+        value: String,
+        options: Vec<String>,
+    },
+    ProblemSensor {
+        name: String,
+        value: bool,
+    },
+    Switch {
+        name: String,
+        value: bool,
+    },
+    Number {
+        name: String,
+        value: f32,
+        min: f32,
+        max: f32,
+        unit: String,
+    },
+}
+
+impl EntityType {
+    pub fn name(&self) -> &str {
+        match self {
+            EntityType::PowerSensor { name, .. }
+            | EntityType::ApparentPowerSensor { name, .. }
+            | EntityType::TemperatureSensor { name, .. }
+            | EntityType::EnergySensor { name, .. }
+            | EntityType::VoltageSensor { name, .. }
+            | EntityType::CurrentSensor { name, .. }
+            | EntityType::FrequencySensor { name, .. }
+            | EntityType::ResistanceSensor { name, .. }
+            | EntityType::DurationSensor { name, .. }
+            | EntityType::TotalDurationSensor { name, .. }
+            | EntityType::BatterySensor { name, .. }
+            | EntityType::PercentageSensor { name, .. }
+            | EntityType::TimestampSensor { name, .. }
+            | EntityType::GenericSensor { name, .. }
+            | EntityType::GenericDiscreteSensor { name, .. }
+            | EntityType::EnumSensor { name, .. }
+            | EntityType::ProblemSensor { name, .. }
+            | EntityType::Switch { name, .. }
+            | EntityType::Number { name, .. } => name,
+        }
+    }
+
+    pub fn component(&self) -> &'static str {
+        match self {
+            EntityType::ProblemSensor { .. } => "binary_sensor",
+            EntityType::Switch { .. } => "switch",
+            EntityType::Number { .. } => "number",
+            _ => "sensor",
+        }
+    }
+
+    pub fn state(&self) -> String {
+        match self {
+            EntityType::PowerSensor { value, .. }
+            | EntityType::ApparentPowerSensor { value, .. } => value.to_string(),
+            EntityType::TemperatureSensor { value, .. } => value.to_string(),
+            EntityType::EnergySensor { value, .. } => value.to_string(),
+            EntityType::VoltageSensor { value, .. } => value.to_string(),
+            EntityType::CurrentSensor { value, .. } => value.to_string(),
+            EntityType::FrequencySensor { value, .. } => value.to_string(),
+            EntityType::ResistanceSensor { value, .. } => value.to_string(),
+            EntityType::DurationSensor { value, .. }
+            | EntityType::TotalDurationSensor { value, .. } => value.to_string(),
+            EntityType::BatterySensor { value, .. } => value.to_string(),
+            EntityType::PercentageSensor { value, .. } => value.to_string(),
+            EntityType::TimestampSensor { value, .. } => rfc3339(*value),
+            EntityType::GenericSensor { value, .. } => value.to_string(),
+            EntityType::GenericDiscreteSensor { value, .. } => value.to_string(),
+            EntityType::EnumSensor { value, .. } => value.to_string(),
+            EntityType::ProblemSensor { value, .. } | EntityType::Switch { value, .. } => {
+                String::from(if *value { "ON" } else { "OFF" })
+            }
+            EntityType::Number { value, .. } => value.to_string(),
+        }
+    }
+
+    /// Value of numeric and binary entities, used for metrics.
+    pub fn numeric_value(&self) -> Option<f64> {
+        match self {
+            EntityType::PowerSensor { value, .. }
+            | EntityType::ApparentPowerSensor { value, .. }
+            | EntityType::DurationSensor { value, .. }
+            | EntityType::TotalDurationSensor { value, .. } => Some(f64::from(*value)),
+            EntityType::EnergySensor { value, .. } => Some(*value),
+            EntityType::TimestampSensor { value, .. } => Some(*value as f64),
+            EntityType::TemperatureSensor { value, .. }
+            | EntityType::VoltageSensor { value, .. }
+            | EntityType::CurrentSensor { value, .. }
+            | EntityType::FrequencySensor { value, .. }
+            | EntityType::GenericSensor { value, .. }
+            | EntityType::Number { value, .. } => Some(f64::from(*value)),
+            EntityType::ResistanceSensor { value, .. }
+            | EntityType::BatterySensor { value, .. }
+            | EntityType::PercentageSensor { value, .. } => Some(f64::from(*value)),
+            EntityType::ProblemSensor { value, .. } | EntityType::Switch { value, .. } => {
+                Some(f64::from(u8::from(*value)))
+            }
+            EntityType::GenericDiscreteSensor { .. } | EntityType::EnumSensor { .. } => None,
+        }
+    }
+
+    /// Multiplies the value of a measurement sensor by given correction factor.
+    ///
+    /// Settings and non-numeric entities are left untouched.
+    pub fn scale(&mut self, factor: f64) {
+        match self {
+            EntityType::PowerSensor { value, .. }
+            | EntityType::ApparentPowerSensor { value, .. }
+            | EntityType::DurationSensor { value, .. }
+            | EntityType::TotalDurationSensor { value, .. } => {
+                *value = (f64::from(*value) * factor).round() as u32;
+            }
+            EntityType::EnergySensor { value, .. } => *value *= factor,
+            EntityType::TemperatureSensor { value, .. }
+            | EntityType::VoltageSensor { value, .. }
+            | EntityType::CurrentSensor { value, .. }
+            | EntityType::FrequencySensor { value, .. }
+            | EntityType::GenericSensor { value, .. } => {
+                *value = (f64::from(*value) * factor) as f32;
+            }
+            EntityType::ResistanceSensor { value, .. }
+            | EntityType::BatterySensor { value, .. }
+            | EntityType::PercentageSensor { value, .. } => {
+                *value = (f64::from(*value) * factor).round() as u16;
+            }
+            EntityType::TimestampSensor { .. }
+            | EntityType::GenericDiscreteSensor { .. }
+            | EntityType::EnumSensor { .. }
+            | EntityType::ProblemSensor { .. }
+            | EntityType::Switch { .. }
+            | EntityType::Number { .. } => {}
+        }
+    }
 }
 
 impl Entity {
-    pub fn power_sensor(name: String, prefix: String, device: &Device) -> Self {
+    pub fn from_entity_type(entity: &EntityType, prefix: String, device: &Device) -> Self {
+        let name = entity.name().to_string();
+
+        match entity {
+            EntityType::PowerSensor { .. } => Entity::power_sensor(name, prefix, device),
+            EntityType::ApparentPowerSensor { .. } => {
+                Entity::apparent_power_sensor(name, prefix, device)
+            }
+            EntityType::TemperatureSensor { .. } => {
+                Entity::temperature_entity(name, prefix, device)
+            }
+            EntityType::EnergySensor { .. } => Entity::energy_sensor(name, prefix, device),
+            EntityType::VoltageSensor { .. } => Entity::voltage_sensor(name, prefix, device),
+            EntityType::CurrentSensor { .. } => Entity::current_sensor(name, prefix, device),
+            EntityType::FrequencySensor { .. } => Entity::frequency_sensor(name, prefix, device),
+            EntityType::ResistanceSensor { .. } => Entity::resistance_sensor(name, prefix, device),
+            EntityType::DurationSensor { .. } => Entity::duration_sensor(name, prefix, device),
+            EntityType::TotalDurationSensor { .. } => {
+                Entity::total_duration_sensor(name, prefix, device)
+            }
+            EntityType::BatterySensor { .. } => Entity::battery_sensor(name, prefix, device),
+            EntityType::PercentageSensor { .. } => Entity::percentage_sensor(name, prefix, device),
+            EntityType::TimestampSensor { .. } => Entity::timestamp_sensor(name, prefix, device),
+            EntityType::GenericSensor { .. } => Entity::generic_sensor(name, prefix, device, false),
+            EntityType::GenericDiscreteSensor { .. } => {
+                Entity::generic_sensor(name, prefix, device, true)
+            }
+            EntityType::EnumSensor { options, .. } => {
+                Entity::enum_sensor(name, prefix, device, options)
+            }
+            EntityType::ProblemSensor { .. } => Entity::problem_sensor(name, prefix, device),
+            EntityType::Switch { .. } => Entity::switch(name, prefix, device),
+            EntityType::Number { min, max, unit, .. } => {
+                Entity::number(name, prefix, device, *min, *max, unit)
+            }
+        }
+    }
+
+    fn sensor(
+        name: String,
+        prefix: String,
+        device: &Device,
+        unit_of_measurement: Option<&str>,
+        state_class: Option<&str>,
+        device_class: Option<&str>,
+    ) -> Self {
         Entity {
             device: device.to_owned(),
             name: name.to_string(),
-            unique_id: format!("{name}_{prefix}"),
-            object_id: format!("{name}_{prefix}"),
+            unique_id: format!("{name}_{}", device.identifiers),
+            object_id: format!("{name}_{}", device.identifiers),
             qos: 0,
-            unit_of_measurement: Some("W".to_string()),
+            unit_of_measurement: unit_of_measurement.map(str::to_string),
             state_topic: format!("{prefix}/state/{name}"),
-            state_class: Some("measurement".to_string()),
-            device_class: Some("power".to_string()),
+            state_class: state_class.map(str::to_string),
+            device_class: device_class.map(str::to_string),
+            options: None,
+            command_topic: None,
+            min: None,
+            max: None,
             json_attributes_topic: format!("{prefix}/attributes"),
+            availability: vec![],
+            availability_mode: String::from("all"),
         }
     }
 
+    pub fn power_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("W"),
+            Some("measurement"),
+            Some("power"),
+        )
+    }
+
+    pub fn apparent_power_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("VA"),
+            Some("measurement"),
+            Some("apparent_power"),
+        )
+    }
+
     pub fn temperature_entity(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("°C"),
+            Some("measurement"),
+            Some("temperature"),
+        )
+    }
+
+    pub fn energy_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("kWh"),
+            Some("total_increasing"),
+            Some("energy"),
+        )
+    }
+
+    pub fn battery_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("%"),
+            Some("measurement"),
+            Some("battery"),
+        )
+    }
+
+    pub fn percentage_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(name, prefix, device, Some("%"), Some("measurement"), None)
+    }
+
+    pub fn voltage_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("V"),
+            Some("measurement"),
+            Some("voltage"),
+        )
+    }
+
+    pub fn current_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("A"),
+            Some("measurement"),
+            Some("current"),
+        )
+    }
+
+    pub fn frequency_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("Hz"),
+            Some("measurement"),
+            Some("frequency"),
+        )
+    }
+
+    /// Home Assistant has no resistance device class, so only the unit is set.
+    pub fn resistance_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(name, prefix, device, Some("kΩ"), Some("measurement"), None)
+    }
+
+    pub fn duration_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("s"),
+            Some("measurement"),
+            Some("duration"),
+        )
+    }
+
+    pub fn total_duration_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            Some("s"),
+            Some("total_increasing"),
+            Some("duration"),
+        )
+    }
+
+    pub fn timestamp_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(name, prefix, device, None, None, Some("timestamp"))
+    }
+
+    pub fn generic_sensor(name: String, prefix: String, device: &Device, discrete: bool) -> Self {
+        Entity::sensor(
+            name,
+            prefix,
+            device,
+            None,
+            if discrete { None } else { Some("measurement") },
+            None,
+        )
+    }
+
+    pub fn enum_sensor(name: String, prefix: String, device: &Device, options: &[String]) -> Self {
         Entity {
-            device: device.to_owned(),
-            name: name.to_string(),
-            unique_id: format!("{name}_{prefix}"),
-            object_id: format!("{name}_{prefix}"),
-            qos: 0,
-            unit_of_measurement: Some("°C".to_string()),
-            state_topic: format!("{prefix}/state/{name}"),
-            state_class: Some("measurement".to_string()),
-            device_class: Some("temperature".to_string()),
-            json_attributes_topic: format!("{prefix}/attributes"),
+            options: Some(options.to_vec()),
+            ..Entity::sensor(name, prefix, device, None, None, Some("enum"))
         }
     }
 
-    pub fn energy_sensor(name: String, prefix: String, device: &Device) -> Self {
+    pub fn problem_sensor(name: String, prefix: String, device: &Device) -> Self {
+        Entity::sensor(name, prefix, device, None, None, Some("problem"))
+    }
+
+    pub fn switch(name: String, prefix: String, device: &Device) -> Self {
         Entity {
-            device: device.to_owned(),
-            name: name.to_string(),
-            unique_id: format!("{name}_{prefix}"),
-            object_id: format!("{name}_{prefix}"),
-            qos: 0,
-            unit_of_measurement: Some("kWh".to_string()),
-            state_topic: format!("{prefix}/state/{name}"),
-            state_class: Some("total_increasing".to_string()),
-            device_class: Some("energy".to_string()),
-            json_attributes_topic: format!("{prefix}/attributes"),
+            command_topic: Some(format!("{prefix}/set/{name}")),
+            ..Entity::sensor(name, prefix, device, None, None, None)
         }
     }
 
-    pub fn generic_sensor(name: String, prefix: String, device: &Device, discrete: bool) -> Self {
+    pub fn number(
+        name: String,
+        prefix: String,
+        device: &Device,
+        min: f32,
+        max: f32,
+        unit: &str,
+    ) -> Self {
         Entity {
-            device: device.to_owned(),
-            name: name.to_string(),
-            unique_id: format!("{name}_{prefix}"),
-            object_id: format!("{name}_{prefix}"),
-            qos: 0,
-            state_topic: format!("{prefix}/state/{name}"),
-            device_class: None,
-            state_class: if discrete {
-                None
-            } else {
-                Some("measurement".to_string())
-            },
-            unit_of_measurement: None,
-            json_attributes_topic: format!("{prefix}/attributes"),
+            command_topic: Some(format!("{prefix}/set/{name}")),
+            min: Some(min),
+            max: Some(max),
+            ..Entity::sensor(name, prefix, device, Some(unit), None, None)
         }
     }
 }
 
-pub fn entities_from_data(data: &Data) -> Vec<EntityType> {
+/// Diagnostics of the data logger reported in its hello frame.
+pub fn entities_from_hello(hello: &Hello) -> Vec<EntityType> {
     vec![
+        EntityType::PercentageSensor {
+            name: "wifi_signal_strength".to_string(),
+            value: u16::from(hello.signal_quality),
+        },
+        EntityType::GenericDiscreteSensor {
+            name: "firmware".to_string(),
+            value: hello.module_version.trim_matches(char::from(0)).to_string(),
+        },
+        // the logger reports the upload interval in minutes
+        EntityType::DurationSensor {
+            name: "upload_interval".to_string(),
+            value: u32::from(hello.uploading_frequency) * 60,
+        },
+    ]
+}
+
+/// Formats seconds since epoch as UTC RFC 3339 timestamp.
+fn rfc3339(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}+00:00",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+pub fn entities_from_data(data: &Data, phases: Phases) -> Vec<EntityType> {
+    let status = InverterStatus::from_code(data.inverter_status);
+    let has_faults = data.fault_codes().iter().any(|code| *code != 0);
+
+    let mut entities = vec![
         EntityType::PowerSensor {
             name: "current_power".to_string(),
             value: data.current_power,
@@ -167,13 +602,298 @@ pub fn entities_from_data(data: &Data) -> Vec<EntityType> {
             name: "inverter_temperature".to_string(),
             value: data.inverter_temperature,
         },
-        EntityType::GenericDiscreteSensor {
+        EntityType::EnumSensor {
             name: "inverter_status".to_string(),
-            value: data.inverter_status.to_string(),
+            value: status
+                .map_or("unknown", |status| status.as_str())
+                .to_string(),
+            options: InverterStatus::OPTIONS.map(str::to_string).to_vec(),
+        },
+        EntityType::ProblemSensor {
+            name: "inverter_fault".to_string(),
+            value: has_faults || status.is_some_and(|status| status.is_fault()),
         },
         EntityType::EnergySensor {
             name: "total_energy".to_string(),
             value: data.total_energy,
         },
+        EntityType::VoltageSensor {
+            name: "vdc_1".to_string(),
+            value: data.vdc_1,
+        },
+        EntityType::VoltageSensor {
+            name: "vdc_2".to_string(),
+            value: data.vdc_2,
+        },
+        EntityType::CurrentSensor {
+            name: "idc_1".to_string(),
+            value: data.idc_1,
+        },
+        EntityType::CurrentSensor {
+            name: "idc_2".to_string(),
+            value: data.idc_2,
+        },
+        EntityType::FrequencySensor {
+            name: "fac".to_string(),
+            value: data.fac,
+        },
+        EntityType::VoltageSensor {
+            name: "bus_voltage".to_string(),
+            value: data.bus_voltage,
+        },
+        EntityType::VoltageSensor {
+            name: "vice_cpu_input_voltage".to_string(),
+            value: data.vice_cpu_input_voltage,
+        },
+        EntityType::TemperatureSensor {
+            name: "logger_temperature".to_string(),
+            value: f32::from(data.logger_temperature),
+        },
+        EntityType::ResistanceSensor {
+            name: "pv1_insulation_resistance".to_string(),
+            value: data.pv1_insulation_resistance,
+        },
+        EntityType::ResistanceSensor {
+            name: "pv2_insulation_resistance".to_string(),
+            value: data.pv2_insulation_resistance,
+        },
+        EntityType::ResistanceSensor {
+            name: "insulation_impedance".to_string(),
+            value: data.insulation_impedance,
+        },
+        // leakage current and DC injection are reported in mA
+        EntityType::CurrentSensor {
+            name: "leaking_current".to_string(),
+            value: f32::from(data.leaking_current) / 1000.0,
+        },
+        EntityType::CurrentSensor {
+            name: "a_phase_dc_distribution".to_string(),
+            value: f32::from(data.a_phase_dc_distribution) / 1000.0,
+        },
+        EntityType::CurrentSensor {
+            name: "b_phase_dc_distribution".to_string(),
+            value: f32::from(data.b_phase_dc_distribution) / 1000.0,
+        },
+        EntityType::CurrentSensor {
+            name: "c_phase_dc_distribution".to_string(),
+            value: f32::from(data.c_phase_dc_distribution) / 1000.0,
+        },
+        EntityType::DurationSensor {
+            name: "countdown_time".to_string(),
+            value: u32::from(data.countdown_time),
+        },
+        // total generation time is reported in hours
+        EntityType::TotalDurationSensor {
+            name: "total_time".to_string(),
+            value: data.total_time.saturating_mul(3600),
+        },
+    ];
+
+    entities.extend(phase_entities(data, phases));
+
+    if let Some(hybrid) = &data.hybrid {
+        entities.extend(hybrid_entities(hybrid));
+    }
+
+    entities
+}
+
+/// Grid voltage, current and power of every phase in use.
+///
+/// Three-phase inverters additionally get line-to-line voltages, computed
+/// from phase voltages assuming phases shifted by 120°.
+fn phase_entities(data: &Data, phases: Phases) -> Vec<EntityType> {
+    let phase_values = [
+        (data.vac_1, data.iac_1),
+        (data.vac_2, data.iac_2),
+        (data.vac_3, data.iac_3),
+    ];
+    let mut entities = vec![];
+
+    for (phase, (voltage, current)) in phase_values.into_iter().enumerate().take(phases.count()) {
+        let phase = phase + 1;
+        entities.extend([
+            EntityType::VoltageSensor {
+                name: format!("vac_{phase}"),
+                value: voltage,
+            },
+            EntityType::CurrentSensor {
+                name: format!("iac_{phase}"),
+                value: current,
+            },
+            // the frame has no per-phase active power
+            EntityType::ApparentPowerSensor {
+                name: format!("sac_{phase}"),
+                value: (voltage * current).round() as u32,
+            },
+        ]);
+    }
+
+    if phases == Phases::Three {
+        let line_voltage = |a: f32, b: f32| (a * a + b * b + a * b).sqrt();
+
+        entities.extend([
+            EntityType::VoltageSensor {
+                name: "vab".to_string(),
+                value: line_voltage(data.vac_1, data.vac_2),
+            },
+            EntityType::VoltageSensor {
+                name: "vbc".to_string(),
+                value: line_voltage(data.vac_2, data.vac_3),
+            },
+            EntityType::VoltageSensor {
+                name: "vca".to_string(),
+                value: line_voltage(data.vac_3, data.vac_1),
+            },
+        ]);
+    }
+
+    entities
+}
+
+/// Battery, grid meter and house load entities of hybrid inverters.
+fn hybrid_entities(data: &HybridData) -> Vec<EntityType> {
+    vec![
+        EntityType::VoltageSensor {
+            name: "battery_voltage".to_string(),
+            value: data.battery_voltage,
+        },
+        EntityType::CurrentSensor {
+            name: "battery_current".to_string(),
+            value: data.battery_current,
+        },
+        // power flows are split, as energy dashboard expects positive values
+        EntityType::PowerSensor {
+            name: "battery_charge_power".to_string(),
+            value: data.battery_power.max(0).unsigned_abs(),
+        },
+        EntityType::PowerSensor {
+            name: "battery_discharge_power".to_string(),
+            value: data.battery_power.min(0).unsigned_abs(),
+        },
+        EntityType::BatterySensor {
+            name: "battery_soc".to_string(),
+            value: data.battery_soc,
+        },
+        EntityType::PercentageSensor {
+            name: "battery_soh".to_string(),
+            value: data.battery_soh,
+        },
+        EntityType::TemperatureSensor {
+            name: "battery_temperature".to_string(),
+            value: data.battery_temperature,
+        },
+        EntityType::PowerSensor {
+            name: "grid_import_power".to_string(),
+            value: data.grid_power.max(0).unsigned_abs(),
+        },
+        EntityType::PowerSensor {
+            name: "grid_export_power".to_string(),
+            value: data.grid_power.min(0).unsigned_abs(),
+        },
+        EntityType::PowerSensor {
+            name: "load_power".to_string(),
+            value: data.load_power,
+        },
+        EntityType::EnergySensor {
+            name: "grid_import_energy".to_string(),
+            value: data.grid_import_energy,
+        },
+        EntityType::EnergySensor {
+            name: "grid_export_energy".to_string(),
+            value: data.grid_export_energy,
+        },
+        EntityType::EnergySensor {
+            name: "battery_charge_energy".to_string(),
+            value: data.battery_charge_energy,
+        },
+        EntityType::EnergySensor {
+            name: "battery_discharge_energy".to_string(),
+            value: data.battery_discharge_energy,
+        },
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{entities_from_data, rfc3339, Entity, EntityType};
+    use crate::{
+        codec::DATA,
+        messages::{Data, HybridData, Phases},
+        sink::tests::inverter,
+    };
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00+00:00");
+        assert_eq!(rfc3339(951782400), "2000-02-29T00:00:00+00:00");
+        assert_eq!(rfc3339(1684481932), "2023-05-19T07:38:52+00:00");
+    }
+
+    #[test]
+    fn total_time_is_total_increasing() {
+        let data = bincode::deserialize(&[0; 151]).unwrap();
+        let entities = entities_from_data(&data, Phases::Single);
+        let total_time = entities
+            .iter()
+            .find(|entity| entity.name() == "total_time")
+            .unwrap();
+
+        let entity = Entity::from_entity_type(total_time, String::new(), &inverter().device);
+        assert_eq!(entity.state_class.as_deref(), Some("total_increasing"));
+    }
+
+    #[test]
+    fn hybrid_entities() {
+        let mut data: Data = bincode::deserialize(&[0; 151]).unwrap();
+        data.hybrid = Some(HybridData {
+            battery_voltage: 512.0,
+            battery_current: -12.5,
+            battery_power: -640,
+            battery_soc: 87,
+            battery_soh: 98,
+            battery_temperature: 25.3,
+            grid_power: -1200,
+            load_power: 950,
+            grid_import_energy: 1234.5,
+            grid_export_energy: 2345.6,
+            battery_charge_energy: 345.6,
+            battery_discharge_energy: 456.7,
+        });
+
+        let entities = entities_from_data(&data, data.phases());
+        let state = |name: &str| {
+            entities
+                .iter()
+                .find(|entity| entity.name() == name)
+                .unwrap()
+                .state()
+        };
+        assert_eq!(state("battery_discharge_power"), "640");
+        assert_eq!(state("battery_charge_power"), "0");
+        assert_eq!(state("grid_export_power"), "1200");
+        assert_eq!(state("battery_temperature"), "25.3");
+    }
+
+    #[test]
+    fn three_phase_entities() {
+        let data = Data::from_bytes(&DATA[11..DATA.len() - 2], DATA.len() - 13).unwrap();
+        assert_eq!(data.phases(), Phases::Three);
+
+        let entities = |phases| entities_from_data(&data, phases);
+        let three_phase = entities(Phases::Three);
+        let apparent_power = three_phase
+            .iter()
+            .find(|entity| entity.name() == "sac_3")
+            .unwrap();
+        let entity = Entity::from_entity_type(apparent_power, String::new(), &inverter().device);
+        assert_eq!(entity.unit_of_measurement.as_deref(), Some("VA"));
+        assert!(three_phase.iter().any(|entity| entity.name() == "vca"));
+
+        let single_phase = entities(Phases::Single);
+        let names: Vec<_> = single_phase.iter().map(EntityType::name).collect();
+        assert!(names.contains(&"vac_1"));
+        assert!(!names.contains(&"vac_2"));
+        assert!(!names.contains(&"vab"));
+    }
+}
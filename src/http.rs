@@ -0,0 +1,31 @@
+use crate::{
+    api::{self, Readings},
+    metrics::Metrics,
+};
+use anyhow::Context;
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use std::net::SocketAddr;
+use tracing::info;
+
+/// Serves the HTTP endpoints until the server fails.
+pub async fn serve(port: u16, metrics: Metrics, readings: Readings) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(metrics.clone())
+        .merge(api::router(readings, metrics));
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+
+    info!("Serving HTTP on {address}");
+    axum::Server::try_bind(&address)
+        .with_context(|| format!("Error binding HTTP server to {address}"))?
+        .serve(app.into_make_service())
+        .await
+        .context("HTTP server failed")
+}
+
+async fn metrics_handler(State(metrics): State<Metrics>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
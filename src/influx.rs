@@ -0,0 +1,380 @@
+use crate::{
+    config::Config,
+    homeassistant::EntityType,
+    messages::Data,
+    sink::{InverterInfo, Sink},
+};
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use hyper::{client::HttpConnector, header, Body, Client, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task, time,
+};
+use tracing::{debug, error, warn};
+
+const MEASUREMENT: &str = "sofar";
+/// Lines kept while the database is unreachable, oldest are dropped first.
+const MAX_PENDING_LINES: usize = 100_000;
+const LINES_CAPACITY: usize = 1000;
+/// Time allowed for a single write, a hung server must not stall the writer.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writes readings to InfluxDB v2 using the line protocol.
+///
+/// Lines are batched by a background task and flushed when the batch is full
+/// or the flush interval elapses. Failed batches are kept and retried on the
+/// next flush.
+pub struct InfluxSink {
+    sender: mpsc::Sender<String>,
+    flush_sender: mpsc::Sender<oneshot::Sender<()>>,
+}
+
+impl InfluxSink {
+    /// Creates the sink when `influx_url` is configured.
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let (Some(url), Some(org), Some(bucket)) = (
+            &config.influx_url,
+            &config.influx_org,
+            &config.influx_bucket,
+        ) else {
+            return Ok(None);
+        };
+
+        Self::new(
+            url,
+            org,
+            bucket,
+            config.influx_token.clone(),
+            config.influx_batch_size,
+            Duration::from_secs(config.influx_flush_interval),
+        )
+        .map(Some)
+    }
+
+    pub fn new(
+        url: &str,
+        org: &str,
+        bucket: &str,
+        token: Option<String>,
+        batch_size: usize,
+        flush_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        let write_url: Uri = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=s",
+            url.trim_end_matches('/'),
+            encode_query(org),
+            encode_query(bucket)
+        )
+        .parse()
+        .with_context(|| format!("Invalid InfluxDB URL {url}"))?;
+
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let writer = InfluxWriter {
+            client: Client::builder().build(connector),
+            write_url,
+            token,
+            batch_size,
+            pending: VecDeque::new(),
+        };
+        let (sender, receiver) = mpsc::channel(LINES_CAPACITY);
+        let (flush_sender, flush_receiver) = mpsc::channel(1);
+
+        task::spawn(writer.run(receiver, flush_receiver, flush_interval));
+
+        Ok(InfluxSink {
+            sender,
+            flush_sender,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    async fn on_data(
+        &self,
+        inverter: &InverterInfo,
+        _data: &Data,
+        entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        if let Some(line) = line(inverter, entities, timestamp) {
+            self.sender
+                .send(line)
+                .await
+                .map_err(|_| anyhow!("InfluxDB writer stopped"))?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let (done, flushed) = oneshot::channel();
+
+        self.flush_sender
+            .send(done)
+            .await
+            .map_err(|_| anyhow!("InfluxDB writer stopped"))?;
+        flushed
+            .await
+            .map_err(|_| anyhow!("InfluxDB writer stopped"))
+    }
+}
+
+struct InfluxWriter {
+    client: Client<HttpsConnector<HttpConnector>>,
+    write_url: Uri,
+    token: Option<String>,
+    batch_size: usize,
+    pending: VecDeque<String>,
+}
+
+impl InfluxWriter {
+    async fn run(
+        mut self,
+        mut receiver: mpsc::Receiver<String>,
+        mut flush_receiver: mpsc::Receiver<oneshot::Sender<()>>,
+        flush_interval: Duration,
+    ) {
+        let mut interval = time::interval(flush_interval);
+
+        loop {
+            tokio::select! {
+                line = receiver.recv() => match line {
+                    Some(line) => {
+                        self.push(line);
+
+                        if self.pending.len() >= self.batch_size {
+                            self.flush().await;
+                        }
+                    }
+                    None => {
+                        self.flush().await;
+                        return;
+                    }
+                },
+                Some(done) = flush_receiver.recv() => {
+                    // lines sent before the request may still be queued
+                    while let Ok(line) = receiver.try_recv() {
+                        self.push(line);
+                    }
+                    self.flush().await;
+                    let _ = done.send(());
+                }
+                _ = interval.tick() => self.flush().await,
+            }
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.pending.len() == MAX_PENDING_LINES {
+            warn!("InfluxDB unreachable, dropping oldest reading");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(line);
+    }
+
+    /// Writes pending lines in batches, stopping at the first failure.
+    async fn flush(&mut self) {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(self.batch_size);
+            let body = self
+                .pending
+                .iter()
+                .take(count)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            let result = time::timeout(WRITE_TIMEOUT, self.write(body))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Timed out after {WRITE_TIMEOUT:?}")));
+
+            match result {
+                Ok(()) => {
+                    debug!("Wrote {count} lines to InfluxDB");
+                    self.pending.drain(..count);
+                }
+                Err(err) => {
+                    error!(
+                        "Error writing to InfluxDB, retrying {} lines later ({err:#})",
+                        self.pending.len()
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn write(&self, body: String) -> anyhow::Result<()> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.write_url)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Token {token}"));
+        }
+
+        let response = self.client.request(request.body(Body::from(body))?).await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            return Err(anyhow!(
+                "{status} {}",
+                String::from_utf8_lossy(&body).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Formats numeric entities as a single line protocol point.
+fn line(inverter: &InverterInfo, entities: &[EntityType], timestamp: u64) -> Option<String> {
+    let fields = entities
+        .iter()
+        .filter_map(|entity| {
+            let value = entity.numeric_value()?;
+            Some(format!("{}={value}", escape(entity.name())))
+        })
+        .collect::<Vec<_>>();
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "{MEASUREMENT},inverter={},logger={} {} {timestamp}",
+        escape(&inverter.serial),
+        inverter.data_logger_sn,
+        fields.join(",")
+    ))
+}
+
+/// Escapes tag keys, tag values and field keys.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Router};
+    use tokio::sync::mpsc;
+
+    use super::InfluxSink;
+    use crate::{
+        homeassistant::EntityType,
+        sink::{tests::inverter, Sink},
+    };
+
+    #[derive(Clone)]
+    struct Received {
+        attempts: Arc<Mutex<usize>>,
+        bodies: mpsc::UnboundedSender<String>,
+    }
+
+    /// Rejects the first write to exercise retries.
+    async fn write(State(received): State<Received>, body: String) -> StatusCode {
+        let mut attempts = received.attempts.lock().unwrap();
+        *attempts += 1;
+
+        if *attempts == 1 {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        received.bodies.send(body).unwrap();
+        StatusCode::NO_CONTENT
+    }
+
+    #[tokio::test]
+    async fn retries_failed_batches() {
+        let (bodies, mut received_bodies) = mpsc::unbounded_channel();
+        let received = Received {
+            attempts: Arc::default(),
+            bodies,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/api/v2/write", post(write))
+            .with_state(received.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let sink =
+            InfluxSink::new(&url, "home", "solar", None, 2, Duration::from_millis(50)).unwrap();
+        let inverter = inverter();
+        let data = bincode::deserialize(&[0; 151]).unwrap();
+
+        for value in [310, 320] {
+            let entities = [
+                EntityType::PowerSensor {
+                    name: "current_power".to_string(),
+                    value,
+                },
+                EntityType::EnumSensor {
+                    name: "inverter_status".to_string(),
+                    value: "normal".to_string(),
+                    options: vec![],
+                },
+            ];
+            sink.on_data(&inverter, &data, &entities).await.unwrap();
+        }
+
+        // the batch is written once the flush interval retries it
+        let body = received_bodies.recv().await.unwrap();
+        let lines: Vec<_> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0]
+            .starts_with("sofar,inverter=sf4es003m4c058,logger=1744743503 current_power=310 "));
+        assert!(lines[1].contains(" current_power=320 "));
+
+        // an incomplete batch is written right away on flush
+        let entities = [EntityType::PowerSensor {
+            name: "current_power".to_string(),
+            value: 330,
+        }];
+        sink.on_data(&inverter, &data, &entities).await.unwrap();
+        sink.flush().await.unwrap();
+        assert!(received_bodies
+            .try_recv()
+            .unwrap()
+            .contains(" current_power=330 "));
+    }
+}
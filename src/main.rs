@@ -3,143 +3,683 @@ extern crate enum_primitive_derive;
 extern crate dotenv;
 extern crate num_traits;
 
+mod api;
+mod capture;
+mod cli;
+mod cloud;
 mod codec;
+mod commands;
 mod config;
+mod energy;
 mod homeassistant;
+mod http;
+mod influx;
 mod logger;
 mod messages;
+mod metrics;
+mod modbus;
 mod mqtt;
+mod registry;
 mod serde_helpers;
+mod sink;
+mod state;
+mod status;
+mod tls;
 
 use crate::{
-    codec::SofarCodec,
-    config::Config,
-    homeassistant::{entities_from_data, Attributes, Device},
-    messages::{IncomingMessageData, SofarMessage},
-    mqtt::MqttPublisher,
+    api::Readings,
+    capture::{read_capture, Capture, CapturedStream},
+    cli::{Cli, Commands},
+    cloud::write_frames,
+    codec::{RawFrameCodec, SofarCodec},
+    commands::{Command, CommandRouter, PendingRequests, Requester, Setting},
+    config::{Config, InverterConfig},
+    homeassistant::{entities_from_data, entities_from_hello, Device, EntityType},
+    influx::InfluxSink,
+    messages::{IncomingMessageData, SofarMessage, SofarMessageType},
+    metrics::Metrics,
+    modbus::ModbusResponse,
+    mqtt::{run_event_loop, MqttPublisher},
+    registry::{Attachment, LoggerRegistry},
+    sink::{InverterInfo, LoggerInfo, Sink, Sinks},
+    state::{HelloInfo, StateStore},
 };
 use anyhow::Context;
+use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    task,
+    signal,
+    sync::mpsc,
+    task::{self, JoinSet},
+    time,
+};
+use tokio_util::{
+    codec::{Framed, FramedRead},
+    sync::CancellationToken,
 };
-use tokio_util::codec::Framed;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+
+/// Maximum number of MQTT commands waiting for a single logger connection.
+const COMMANDS_CAPACITY: usize = 8;
+const REPLAY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// State shared by all connection handlers.
+#[derive(Clone)]
+struct Bridge {
+    config: Arc<Config>,
+    command_router: CommandRouter,
+    metrics: Metrics,
+    state_store: StateStore,
+    registry: LoggerRegistry,
+    /// Cancelled on shutdown, connections close after the current frame
+    shutdown: CancellationToken,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+
+    if cli.print_config {
+        print!("{}", toml::to_string(&config.redacted())?);
+        return Ok(());
+    }
+
     logger::init_logger()?;
 
     info!("Starting sofar-mqtt v{}", env!("CARGO_PKG_VERSION"));
 
-    let config = serde_env::from_env::<Config>()?;
+    let config = Arc::new(config);
+
+    let command_router = CommandRouter::default();
+    let metrics = Metrics::new();
+    let (mqtt_publisher, event_loop) = MqttPublisher::new(&config, metrics.clone())?;
+    let event_loop = task::spawn(run_event_loop(
+        event_loop,
+        mqtt_publisher.clone(),
+        command_router.clone(),
+    ));
+
+    let mut sinks = Sinks::default();
+    sinks.push(mqtt_publisher.clone());
+    let state_store = StateStore::load(config.state_file.as_ref()).await?;
+    let readings = Readings::new();
+    let registry = LoggerRegistry::default();
+    let state = state_store.all().await;
+    readings.restore(&state);
+    registry.restore(&state);
+    if config.http_port.is_some() {
+        sinks.push(metrics.clone());
+        sinks.push(readings.clone());
+    }
+    if let Some(influx_sink) = InfluxSink::from_config(&config)? {
+        sinks.push(influx_sink);
+    }
+
+    let bridge = Bridge {
+        config: config.clone(),
+        command_router,
+        metrics: metrics.clone(),
+        state_store,
+        registry,
+        shutdown: CancellationToken::new(),
+    };
+
+    if let Some(Commands::Replay { file }) = &cli.command {
+        replay(file, &sinks, &bridge).await?;
+        sinks.flush().await?;
+        mqtt_publisher.disconnect().await?;
+        event_loop.await?;
+        return Ok(());
+    }
+
+    let capture = match &config.capture_file {
+        Some(path) => Some(Capture::open(path).await?),
+        None => None,
+    };
+
+    if let Some(http_port) = config.http_port {
+        let metrics = metrics.clone();
+        task::spawn(async move {
+            if let Err(err) = http::serve(http_port, metrics, readings).await {
+                error!("{err:?}");
+            }
+        });
+    }
+
     let listener = TcpListener::bind(format!("0.0.0.0:{0}", config.tcp_port)).await?;
     info!("Waiting for connections");
 
+    let mut connections = JoinSet::new();
+    let shutdown_signal = shutdown_signal();
+    tokio::pin!(shutdown_signal);
+
     loop {
-        let (mut socket, _connection) = listener.accept().await?;
-        task::spawn(async move {
-            let result = process_socket(&mut socket).await.with_context(|| {
-                format!(
-                    "Finished connection to {} with error",
-                    &socket.peer_addr().unwrap(),
-                )
-            });
+        let (socket, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // reap finished connections, so the set does not grow
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = &mut shutdown_signal => break,
+        };
+        let stream = CapturedStream::new(socket, peer, capture.clone());
+        let sinks = sinks.clone();
+        let bridge = bridge.clone();
+        connections.spawn(async move {
+            let cloud = match &bridge.config.cloud_address {
+                Some(address) => match cloud::connect(address).await {
+                    Ok(cloud) => Some(cloud),
+                    Err(err) => {
+                        error!("{err:?}, acknowledging frames locally");
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            bridge.metrics.connection_opened();
+            let result = process_socket(stream, cloud, peer, &sinks, &bridge)
+                .await
+                .with_context(|| format!("Finished connection to {peer} with error"));
+            bridge.metrics.connection_closed();
 
             if let Err(err) = result {
                 error!("{err:?}")
             }
         });
     }
+
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+    info!("Shutting down, waiting up to {shutdown_timeout:?}");
+    drop(listener);
+    bridge.shutdown.cancel();
+
+    let shutdown = async {
+        while connections.join_next().await.is_some() {}
+        if let Some(capture) = &capture {
+            if let Err(err) = capture.flush().await {
+                error!("{err}");
+            }
+        }
+        sinks.flush().await?;
+        mqtt_publisher.disconnect().await?;
+        event_loop.await?;
+        anyhow::Ok(())
+    };
+    match time::timeout(shutdown_timeout, shutdown).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Shutdown did not finish in {shutdown_timeout:?}, exiting");
+            Ok(())
+        }
+    }
+}
+
+/// Completes on `SIGINT`, or `SIGTERM` sent by `docker stop`.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Error listening for SIGTERM ({err})");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    let interrupt = async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("Error listening for SIGINT ({err})");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// Feeds recorded connections through [`process_socket`], one after another.
+///
+/// Responses and Modbus requests meant for the data logger are discarded.
+/// Recorded frames are old, so they start from a blank state and registry
+/// instead of overwriting the live ones.
+async fn replay(path: &Path, sink: &dyn Sink, bridge: &Bridge) -> anyhow::Result<()> {
+    let bridge = &Bridge {
+        state_store: StateStore::load(None::<&Path>).await?,
+        registry: LoggerRegistry::default(),
+        ..bridge.clone()
+    };
+    let mut connections: Vec<(SocketAddr, Vec<u8>)> = vec![];
+    for record in read_capture(path)? {
+        match connections
+            .iter_mut()
+            .find(|(peer, _)| *peer == record.peer)
+        {
+            Some((_, data)) => data.extend(record.data),
+            None => connections.push((record.peer, record.data)),
+        }
+    }
+
+    info!(
+        "Replaying {} connections from {}",
+        connections.len(),
+        path.display()
+    );
+
+    for (peer, data) in connections {
+        let (client, server) = tokio::io::duplex(REPLAY_BUFFER_SIZE);
+        let (mut responses, mut requests) = tokio::io::split(client);
+
+        let feeder = task::spawn(async move {
+            requests.write_all(&data).await?;
+            requests.shutdown().await
+        });
+        let drain =
+            task::spawn(
+                async move { tokio::io::copy(&mut responses, &mut tokio::io::sink()).await },
+            );
+
+        process_socket(server, None::<TcpStream>, peer, sink, bridge)
+            .await
+            .with_context(|| format!("Error replaying connection from {peer}"))?;
+
+        feeder.await??;
+        drain.await??;
+    }
+
+    Ok(())
 }
 
 #[tracing::instrument(
     skip_all,
-    fields(ip = %stream.peer_addr().unwrap()),
+    fields(ip = %peer),
 )]
-async fn process_socket(stream: &mut TcpStream) -> anyhow::Result<()> {
+async fn process_socket(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    cloud: Option<impl AsyncRead + AsyncWrite + Unpin>,
+    peer: SocketAddr,
+    sink: &dyn Sink,
+    bridge: &Bridge,
+) -> anyhow::Result<()> {
     info!("Spawning connection handler");
 
-    let mut inverter_ip: Option<String> = None;
-    let mut module_version: Option<String> = None;
+    let Bridge {
+        config,
+        command_router,
+        metrics,
+        state_store,
+        registry,
+        shutdown,
+    } = bridge;
+
+    let offline_timeout = Duration::from_secs(config.inverter_offline_timeout);
+    let mut inverter: Option<InverterInfo> = None;
+    let mut inverter_overrides = InverterConfig::default();
+    let mut logger: Option<LoggerInfo> = None;
+    let mut attachment: Option<Attachment> = None;
+    let mut replaced = false;
+    let mut online = false;
+    let mut pending_requests = PendingRequests::default();
+    let (command_sender, mut command_receiver) = mpsc::channel::<Command>(COMMANDS_CAPACITY);
+    let mut cloud = cloud.map(|cloud| {
+        let (reader, writer) = tokio::io::split(cloud);
+        (FramedRead::new(reader, RawFrameCodec::default()), writer)
+    });
     let mut framed_stream = Framed::new(stream, SofarCodec::default());
+    let mut reported_dropped_frames = 0;
+    let mut reported_dropped_bytes = 0;
+    // only frames from the data logger keep the inverter online, not commands or cloud frames
+    let offline_deadline = time::sleep(offline_timeout);
+    tokio::pin!(offline_deadline);
+
+    let result = async {
+        loop {
+            let frame = tokio::select! {
+                frame = framed_stream.next() => match frame {
+                    Some(frame) => {
+                        offline_deadline
+                            .as_mut()
+                            .reset(time::Instant::now() + offline_timeout);
+                        frame
+                    }
+                    None => break,
+                },
+                _ = &mut offline_deadline => {
+                    offline_deadline
+                        .as_mut()
+                        .reset(time::Instant::now() + offline_timeout);
 
-    while let Some(frame) = framed_stream.next().await {
-        match frame {
-            Err(err) => error!("Error while reading frame ({:#?})", err),
-            Ok(message) => {
-                info!("Received frame of type {:?}", message.message_type);
-
-                let response_message =
-                    SofarMessage::from_incoming_message(&message, current_timestamp());
-
-                match message.data {
-                    IncomingMessageData::Data(data) => {
-                        framed_stream.send(response_message).await?;
-
-                        let device = Device {
-                            configuration_url: inverter_ip
-                                .clone()
-                                .map(|ip| format!("http://{}/index_cn.html", ip)),
-                            identifiers: format!(
-                                "sofar_{}",
-                                data.inverter_serial_number.trim().to_lowercase()
-                            ),
-                            manufacturer: String::from("Sofar"),
-                            model: data.inverter_serial_number.trim().to_string(),
-                            name: format!("Sofar {}", data.inverter_serial_number.trim()),
-                            sw_version: module_version.to_owned(),
-                        };
-                        let attributes = Attributes::from_data(&data);
-                        let entities = entities_from_data(&data);
-
-                        let mut mqtt_publisher = MqttPublisher::new(format!(
-                            "sofar_{}",
-                            data.inverter_serial_number.trim().to_lowercase()
-                        ));
-
-                        info!("Sending data to MQTT broker");
-                        info!("Sending attributes ({:?})", attributes);
-
-                        mqtt_publisher
-                            .publish_attributes(&serde_json::to_value(&attributes)?)
+                    if let (Some(inverter), true) = (&inverter, online) {
+                        info!("No frames received in {offline_timeout:?}, marking inverter offline");
+                        sink.on_availability(inverter, false).await?;
+                        online = false;
+                    }
+                    continue;
+                }
+                _ = shutdown.cancelled() => break,
+                _ = async { (&mut attachment.as_mut().unwrap().replaced).await },
+                    if attachment.is_some() =>
+                {
+                    replaced = true;
+                    break;
+                }
+                Some(command) = command_receiver.recv() => {
+                    info!("Received command {:?}", command);
+
+                    if let Some(logger) = &logger {
+                        let request = command
+                            .setting
+                            .write_request(config.modbus_slave_id, command.value);
+                        framed_stream
+                            .send(SofarMessage::modbus_request(
+                                logger.data_logger_sn,
+                                pending_requests.bridge_request(None),
+                                &request,
+                            ))
                             .await?;
+                    }
+                    continue;
+                }
+                frame = async { cloud.as_mut().unwrap().0.next().await }, if cloud.is_some() => {
+                    match frame {
+                        Some(Ok(frame)) => {
+                            debug!("Forwarding {} bytes from cloud server", frame.len());
+                            // framing is checked, so the header is complete
+                            if u16::from_le_bytes([frame[3], frame[4]])
+                                == SofarMessageType::ModbusRequest as u16
+                            {
+                                pending_requests.cloud_request(frame[5]);
+                            }
+                            framed_stream.send(frame).await?;
+                        }
+                        Some(Err(_)) | None => {
+                            warn!("Cloud server closed connection, acknowledging frames locally");
+                            cloud = None;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let codec = framed_stream.codec();
+            metrics.frames_dropped(
+                codec.dropped_frames() - reported_dropped_frames,
+                codec.dropped_bytes() - reported_dropped_bytes,
+            );
+            reported_dropped_frames = codec.dropped_frames();
+            reported_dropped_bytes = codec.dropped_bytes();
 
-                        info!("Sending data ({:?})", entities);
+            match frame {
+                Err(err) => error!("Error while reading frame ({:#?})", err),
+                Ok(message) => {
+                    info!(
+                        "Received frame of type {:?} ({:#06x})",
+                        message.message_type(),
+                        message.control_code
+                    );
+                    metrics.frame_decoded(
+                        &message.message_type().map_or_else(
+                            || format!("{:#06x}", message.control_code),
+                            |message_type| format!("{message_type:?}"),
+                        ),
+                        message.data_logger_sn,
+                    );
 
-                        for entity in entities {
-                            mqtt_publisher.publish_discovery(&entity, &device).await?;
-                            mqtt_publisher.publish_state(&entity).await?;
+                    if logger.is_none() {
+                        let mut info = LoggerInfo::new(message.data_logger_sn);
+
+                        // the hello frame is only sent after the data logger powers up
+                        if let Some(hello) = registry.hello(message.data_logger_sn) {
+                            info.apply_hello(&hello);
                         }
+                        attachment = Some(registry.attach(message.data_logger_sn, peer));
+                        logger = Some(info);
 
-                        mqtt_publisher.event_loop.poll().await?;
+                        // data frames only arrive every few minutes, route commands right away
+                        if let Some(info) = registry.inverter(message.data_logger_sn) {
+                            inverter_overrides = config.inverter(&info.serial, info.data_logger_sn);
+                            command_router.register(&info.serial, command_sender.clone());
+                            inverter = Some(info);
+                        }
+                    }
+                    let logger = logger.as_mut().unwrap();
 
-                        info!("Disconnecting from MQTT broker");
-                        mqtt_publisher.mqtt_client.disconnect().await?;
+                    let requester = match message.data {
+                        IncomingMessageData::ModbusResponse(_) => {
+                            Some(pending_requests.response(message.message_number))
+                        }
+                        _ => None,
+                    };
+
+                    let mut frames = framed_stream.codec_mut().take_frames();
+                    // responses to own requests would confuse the cloud server
+                    if matches!(requester, Some(Requester::Bridge(_))) {
+                        frames.pop();
                     }
-                    IncomingMessageData::Hello(data) => {
-                        inverter_ip = Some(
-                            data.local_ip_address
-                                .trim_matches(char::from(0))
-                                .to_string(),
-                        );
-                        module_version =
-                            Some(data.module_version.trim_matches(char::from(0)).to_string());
-                        framed_stream.send(response_message).await?;
+                    if let Some((_, writer)) = &mut cloud {
+                        if let Err(err) = write_frames(writer, &frames).await {
+                            warn!(
+                                "Error forwarding to cloud server ({err}), \
+                                 acknowledging frames locally"
+                            );
+                            cloud = None;
+                        }
                     }
-                    _ => {
-                        framed_stream.send(response_message).await?;
+
+                    // the cloud server acknowledges forwarded frames itself
+                    if cloud.is_none()
+                        && !matches!(message.data, IncomingMessageData::ModbusResponse(_))
+                    {
+                        framed_stream
+                            .send(SofarMessage::from_incoming_message(
+                                &message,
+                                current_timestamp(),
+                            ))
+                            .await?;
+                    }
+
+                    match message.data {
+                        IncomingMessageData::Data(data) => {
+                            let overrides = config
+                                .inverter(&data.inverter_serial_number, message.data_logger_sn);
+                            let serial = data.inverter_serial_number.trim().to_lowercase();
+                            let hello = registry.hello(message.data_logger_sn);
+                            let info = InverterInfo {
+                                device: Device {
+                                    configuration_url: hello
+                                        .as_ref()
+                                        .map(HelloInfo::configuration_url),
+                                    identifiers: format!("sofar_{serial}"),
+                                    manufacturer: String::from("Sofar"),
+                                    model: overrides.model.clone().unwrap_or_else(|| {
+                                        data.inverter_serial_number.trim().to_string()
+                                    }),
+                                    name: overrides.name.clone().unwrap_or_else(|| {
+                                        format!("Sofar {}", data.inverter_serial_number.trim())
+                                    }),
+                                    sw_version: hello
+                                        .as_ref()
+                                        .map(|hello| hello.module_version.clone()),
+                                    suggested_area: overrides.area.clone(),
+                                    via_device: Some(logger.device.identifiers.clone()),
+                                    connections: vec![],
+                                },
+                                serial,
+                                data_logger_sn: message.data_logger_sn,
+                            };
+                            let phases = overrides.phases.unwrap_or_else(|| data.phases());
+                            let mut entities = entities_from_data(&data, phases);
+                            entities.retain(|entity| entity.name() != "daily_energy");
+                            entities.extend(state_store.update_data(&info, &data).await);
+                            let entities: Vec<_> = entities
+                                .into_iter()
+                                .filter_map(|entity| overrides.apply(entity))
+                                .collect();
+
+                            sink.on_data(&info, &data, &entities).await?;
+                            inverter_overrides = overrides;
+                            registry.update_inverter(info.clone());
+
+                            let serial = info.serial.clone();
+                            let known = inverter
+                                .replace(info)
+                                .is_some_and(|previous| previous.serial == serial);
+
+                            if !known {
+                                command_router.register(&serial, command_sender.clone());
+
+                                // read current settings so they show up in Home Assistant
+                                for setting in Setting::ALL {
+                                    framed_stream
+                                        .send(SofarMessage::modbus_request(
+                                            message.data_logger_sn,
+                                            pending_requests.bridge_request(Some(setting)),
+                                            &setting.read_request(config.modbus_slave_id),
+                                        ))
+                                        .await?;
+                                }
+                            }
+                        }
+                        IncomingMessageData::Hello(data) => {
+                            let info = HelloInfo::new(message.data_logger_sn, &data);
+                            logger.apply_hello(&info);
+                            registry.update_hello(info.clone());
+                            state_store.update_hello(info).await;
+
+                            sink.on_hello(logger, &data).await?;
+                            sink.on_logger_data(logger, &entities_from_hello(&data))
+                                .await?;
+                        }
+                        IncomingMessageData::Heartbeat(_) => {
+                            let timestamp = u64::from(current_timestamp());
+                            registry.update_heartbeat(message.data_logger_sn, timestamp);
+                            sink.on_logger_data(
+                                logger,
+                                &[EntityType::TimestampSensor {
+                                    name: String::from("last_heartbeat"),
+                                    value: timestamp,
+                                }],
+                            )
+                            .await?;
+                        }
+                        IncomingMessageData::Unknown44(data) => {
+                            sink.on_logger_data(
+                                logger,
+                                &[EntityType::GenericDiscreteSensor {
+                                    name: String::from("wifi_ssid"),
+                                    value: data.wifi_ssid.trim_matches(char::from(0)).to_string(),
+                                }],
+                            )
+                            .await?;
+                        }
+                        IncomingMessageData::ModbusResponse(data) => {
+                            match (requester, data.response()) {
+                                (
+                                    Some(Requester::Bridge(_)),
+                                    Ok(ModbusResponse::Written { address, .. }),
+                                ) => {
+                                    info!("Inverter confirmed write to register {address:#06x}");
+
+                                    // read the register back to publish the value actually applied
+                                    if let Some(setting) = Setting::from_register(address) {
+                                        framed_stream
+                                            .send(SofarMessage::modbus_request(
+                                                message.data_logger_sn,
+                                                pending_requests.bridge_request(Some(setting)),
+                                                &setting.read_request(config.modbus_slave_id),
+                                            ))
+                                            .await?;
+                                    }
+                                }
+                                (
+                                    Some(Requester::Bridge(setting)),
+                                    Ok(ModbusResponse::Registers(registers)),
+                                ) => {
+                                    if let (Some(setting), Some(value), Some(inverter)) =
+                                        (setting, registers.first(), &inverter)
+                                    {
+                                        if let Some(entity) =
+                                            inverter_overrides.apply(setting.entity(*value))
+                                        {
+                                            sink.on_setting(inverter, &entity).await?;
+                                        }
+                                    }
+                                }
+                                (
+                                    Some(Requester::Bridge(_)),
+                                    Ok(ModbusResponse::Exception { function, code }),
+                                ) => {
+                                    warn!("Inverter rejected Modbus function {function} ({code})");
+                                }
+                                (Some(Requester::Bridge(_)), Err(err)) => {
+                                    error!("Error while reading Modbus response ({err})")
+                                }
+                                (requester, _) => debug!(
+                                    "Ignoring Modbus response {} of {requester:?} request",
+                                    message.message_number
+                                ),
+                            }
+                        }
+                        IncomingMessageData::Unknown {
+                            control_code,
+                            payload,
+                        } => {
+                            warn!("Received unknown frame {control_code:#06x} ({payload:?})");
+                            sink.on_unknown_frame(message.data_logger_sn, control_code, &payload)
+                                .await?;
+                        }
+                        IncomingMessageData::HelloCd(_) | IncomingMessageData::HelloEnd(_) => {}
+                    }
+
+                    if let (Some(inverter), false) = (&inverter, online) {
+                        sink.on_availability(inverter, true).await?;
+                        online = true;
                     }
                 }
             }
         }
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Some(inverter) = &inverter {
+        command_router.unregister(&inverter.serial, &command_sender);
+    }
+    // a newer connection of the data logger took over, the logger is still online
+    if let (Some(logger), false) = (&logger, replaced) {
+        sink.on_disconnect(logger, inverter.as_ref()).await?;
+    }
+    if let (Some(logger), Some(attachment)) = (&logger, &attachment) {
+        registry.detach(logger.data_logger_sn, attachment);
+    }
+
+    let codec = framed_stream.codec();
+    if codec.dropped_frames() > 0 || codec.dropped_bytes() > 0 {
+        warn!(
+            "Dropped {} corrupt frames and {} bytes from the data logger",
+            codec.dropped_frames(),
+            codec.dropped_bytes()
+        );
     }
 
     info!("Finishing TCP connection");
-    Ok(())
+    result
 }
 
 fn current_timestamp() -> u32 {
@@ -151,3 +691,416 @@ fn current_timestamp() -> u32 {
     )
     .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use bytes::BytesMut;
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        time,
+    };
+    use tokio_util::{
+        codec::{Decoder, Encoder},
+        sync::CancellationToken,
+    };
+
+    use super::{process_socket, Bridge};
+    use crate::{
+        codec::{RawFrameCodec, SofarCodec, DATA, HEARTBEAT},
+        commands::{CommandRouter, Setting},
+        homeassistant::EntityType,
+        messages::{Data, SofarMessage, SofarMessageType},
+        metrics::Metrics,
+        registry::LoggerRegistry,
+        sink::{InverterInfo, LoggerInfo, Sink},
+        state::StateStore,
+    };
+
+    async fn bridge() -> Bridge {
+        Bridge {
+            config: Arc::new(serde_json::from_value(json!({})).unwrap()),
+            command_router: CommandRouter::default(),
+            metrics: Metrics::new(),
+            state_store: StateStore::load(None::<&str>).await.unwrap(),
+            registry: LoggerRegistry::default(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    #[derive(Default)]
+    struct MockSink {
+        events: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Sink for MockSink {
+        async fn on_data(
+            &self,
+            inverter: &InverterInfo,
+            _data: &Data,
+            entities: &[EntityType],
+        ) -> anyhow::Result<()> {
+            let power = entities
+                .iter()
+                .find(|entity| entity.name() == "current_power")
+                .and_then(EntityType::numeric_value);
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("data {} {power:?}", inverter.serial));
+            Ok(())
+        }
+
+        async fn on_availability(
+            &self,
+            inverter: &InverterInfo,
+            online: bool,
+        ) -> anyhow::Result<()> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("availability {} {online}", inverter.serial));
+            Ok(())
+        }
+
+        async fn on_setting(
+            &self,
+            inverter: &InverterInfo,
+            entity: &EntityType,
+        ) -> anyhow::Result<()> {
+            self.events.lock().unwrap().push(format!(
+                "setting {} {}",
+                inverter.serial,
+                entity.name()
+            ));
+            Ok(())
+        }
+
+        async fn on_disconnect(
+            &self,
+            logger: &LoggerInfo,
+            inverter: Option<&InverterInfo>,
+        ) -> anyhow::Result<()> {
+            self.events.lock().unwrap().push(format!(
+                "disconnect {} {:?}",
+                logger.data_logger_sn,
+                inverter.map(|inverter| &inverter.serial)
+            ));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_frames_to_sink() {
+        let sink = MockSink::default();
+        let (mut client, server) = tokio::io::duplex(4096);
+
+        client.write_all(&DATA).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        process_socket(
+            server,
+            None::<DuplexStream>,
+            "10.0.0.64:51234".parse().unwrap(),
+            &sink,
+            &bridge().await,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *sink.events.lock().unwrap(),
+            [
+                "data sf4es003m4c058 Some(310.0)",
+                "availability sf4es003m4c058 true",
+                "disconnect 1744743503 Some(\"sf4es003m4c058\")",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_frames_to_cloud() {
+        let sink = MockSink::default();
+        let bridge = bridge().await;
+        let (mut logger, server) = tokio::io::duplex(4096);
+        let (mut cloud, upstream) = tokio::io::duplex(4096);
+
+        let mut codec = SofarCodec::default();
+        let message = codec
+            .decode(&mut BytesMut::from(&DATA[..]))
+            .unwrap()
+            .unwrap();
+        let mut ack = BytesMut::new();
+        codec
+            .encode(
+                SofarMessage::from_incoming_message(&message, 1684481932),
+                &mut ack,
+            )
+            .unwrap();
+
+        let handler = process_socket(
+            server,
+            Some(upstream),
+            "10.0.0.64:51234".parse().unwrap(),
+            &sink,
+            &bridge,
+        );
+        let peers = async {
+            logger.write_all(&DATA).await.unwrap();
+
+            let mut forwarded = [0; DATA.len()];
+            cloud.read_exact(&mut forwarded).await.unwrap();
+            assert_eq!(forwarded, DATA);
+            cloud.write_all(&ack).await.unwrap();
+
+            // settings are still read locally, next to the forwarded ack
+            let mut received = BytesMut::new();
+            let mut frames = vec![];
+            let mut raw_codec = RawFrameCodec::default();
+            while !frames.contains(&ack) {
+                logger.read_buf(&mut received).await.unwrap();
+                while let Some(frame) = raw_codec.decode(&mut received).unwrap() {
+                    frames.push(BytesMut::from(&frame[..]));
+                }
+            }
+            logger.shutdown().await.unwrap();
+            frames
+        };
+
+        let (result, frames) = tokio::join!(handler, peers);
+        result.unwrap();
+
+        let control_codes: Vec<_> = frames
+            .iter()
+            .map(|frame| u16::from_le_bytes([frame[3], frame[4]]))
+            .filter(|code| *code != SofarMessageType::ModbusRequest as u16)
+            .collect();
+        assert_eq!(control_codes, [message.control_code - 0x3000]);
+    }
+
+    /// Modbus response frame answering request `sequence` with register value 42.
+    fn modbus_response(sequence: u8) -> [u8; 34] {
+        let mut frame = [
+            165, 21, 0, 16, 21, 1, 0, 79, 172, 254, 103, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            1, 3, 2, 0, 42, 57, 155, 0, 21,
+        ];
+        frame[5] = sequence;
+        frame[32] = frame[1..32]
+            .iter()
+            .fold(0, |sum, byte| sum.wrapping_add(*byte));
+        frame
+    }
+
+    #[tokio::test]
+    async fn forwards_only_modbus_responses_to_cloud_requests() {
+        let sink = MockSink::default();
+        let bridge = bridge().await;
+        let (mut logger, server) = tokio::io::duplex(4096);
+        let (mut cloud, upstream) = tokio::io::duplex(4096);
+
+        let mut request = BytesMut::new();
+        SofarCodec::default()
+            .encode(
+                SofarMessage::modbus_request(1744743503, 200, &Setting::Power.read_request(1)),
+                &mut request,
+            )
+            .unwrap();
+
+        let handler = process_socket(
+            server,
+            Some(upstream),
+            "10.0.0.64:51234".parse().unwrap(),
+            &sink,
+            &bridge,
+        );
+        let peers = async {
+            logger.write_all(&DATA).await.unwrap();
+            let mut forwarded = [0; DATA.len()];
+            cloud.read_exact(&mut forwarded).await.unwrap();
+            cloud.write_all(&request).await.unwrap();
+
+            // the bridge reads settings with sequence numbers 1 to 3
+            let mut received = BytesMut::new();
+            let mut frames = vec![];
+            let mut raw_codec = RawFrameCodec::default();
+            while !frames.contains(&request) {
+                logger.read_buf(&mut received).await.unwrap();
+                while let Some(frame) = raw_codec.decode(&mut received).unwrap() {
+                    frames.push(BytesMut::from(&frame[..]));
+                }
+            }
+
+            logger.write_all(&modbus_response(1)).await.unwrap();
+            logger.write_all(&modbus_response(200)).await.unwrap();
+            let mut forwarded = [0; 34];
+            cloud.read_exact(&mut forwarded).await.unwrap();
+            logger.shutdown().await.unwrap();
+            forwarded
+        };
+
+        let (result, forwarded) = tokio::join!(handler, peers);
+        result.unwrap();
+
+        assert_eq!(forwarded, modbus_response(200));
+        assert_eq!(
+            *sink.events.lock().unwrap(),
+            [
+                "data sf4es003m4c058 Some(310.0)",
+                "availability sf4es003m4c058 true",
+                "setting sf4es003m4c058 power",
+                "disconnect 1744743503 Some(\"sf4es003m4c058\")",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn marks_inverter_offline_despite_commands() {
+        let sink = MockSink::default();
+        let bridge = bridge().await;
+        let (mut logger, server) = tokio::io::duplex(4096);
+
+        let handler = process_socket(
+            server,
+            None::<DuplexStream>,
+            "10.0.0.64:51234".parse().unwrap(),
+            &sink,
+            &bridge,
+        );
+        let peers = async {
+            logger.write_all(&DATA).await.unwrap();
+            logger.read_buf(&mut BytesMut::new()).await.unwrap();
+
+            // commands keep coming, but the data logger stays silent
+            for _ in 0..8 {
+                time::sleep(Duration::from_secs(100)).await;
+                bridge.command_router.route("sf4es003m4c058", "power", "ON");
+            }
+            logger.shutdown().await.unwrap();
+        };
+
+        let (result, _) = tokio::join!(handler, peers);
+        result.unwrap();
+
+        assert_eq!(
+            *sink.events.lock().unwrap(),
+            [
+                "data sf4es003m4c058 Some(310.0)",
+                "availability sf4es003m4c058 true",
+                "availability sf4es003m4c058 false",
+                "disconnect 1744743503 Some(\"sf4es003m4c058\")",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_stale_connections() {
+        let stale_sink = MockSink::default();
+        let sink = MockSink::default();
+        let bridge = bridge().await;
+        let (mut stale_logger, stale_server) = tokio::io::duplex(4096);
+        let (mut logger, server) = tokio::io::duplex(4096);
+
+        let stale_handler = process_socket(
+            stale_server,
+            None::<DuplexStream>,
+            "10.0.0.64:51234".parse().unwrap(),
+            &stale_sink,
+            &bridge,
+        );
+        let reconnect = async {
+            stale_logger.write_all(&DATA).await.unwrap();
+            // the ack shows the connection is attached
+            stale_logger.read_buf(&mut BytesMut::new()).await.unwrap();
+
+            logger.write_all(&DATA).await.unwrap();
+            logger.shutdown().await.unwrap();
+            process_socket(
+                server,
+                None::<DuplexStream>,
+                "10.0.0.64:51301".parse().unwrap(),
+                &sink,
+                &bridge,
+            )
+            .await
+        };
+
+        let (stale_result, result) = tokio::join!(stale_handler, reconnect);
+        stale_result.unwrap();
+        result.unwrap();
+
+        assert_eq!(
+            *stale_sink.events.lock().unwrap(),
+            [
+                "data sf4es003m4c058 Some(310.0)",
+                "availability sf4es003m4c058 true",
+            ]
+        );
+        assert_eq!(
+            sink.events.lock().unwrap().last().unwrap(),
+            "disconnect 1744743503 Some(\"sf4es003m4c058\")"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_inverter_of_previous_connections() {
+        let bridge = bridge().await;
+
+        for frame in [&DATA[..], &HEARTBEAT[..]] {
+            let (mut logger, server) = tokio::io::duplex(4096);
+            logger.write_all(frame).await.unwrap();
+            logger.shutdown().await.unwrap();
+
+            let sink = MockSink::default();
+            process_socket(
+                server,
+                None::<DuplexStream>,
+                "10.0.0.64:51234".parse().unwrap(),
+                &sink,
+                &bridge,
+            )
+            .await
+            .unwrap();
+
+            // the heartbeat connection never saw a data frame
+            assert_eq!(
+                sink.events.lock().unwrap().last().unwrap(),
+                "disconnect 1744743503 Some(\"sf4es003m4c058\")"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_connections_on_shutdown() {
+        let sink = MockSink::default();
+        let bridge = bridge().await;
+        let (mut logger, server) = tokio::io::duplex(4096);
+
+        let handler = process_socket(
+            server,
+            None::<DuplexStream>,
+            "10.0.0.64:51234".parse().unwrap(),
+            &sink,
+            &bridge,
+        );
+        let stop = async {
+            logger.write_all(&DATA).await.unwrap();
+            logger.read_buf(&mut BytesMut::new()).await.unwrap();
+            bridge.shutdown.cancel();
+        };
+
+        let (result, _) = tokio::join!(handler, stop);
+        result.unwrap();
+
+        assert_eq!(
+            sink.events.lock().unwrap().last().unwrap(),
+            "disconnect 1744743503 Some(\"sf4es003m4c058\")"
+        );
+    }
+}
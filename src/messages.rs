@@ -1,5 +1,11 @@
-use crate::serde_helpers::{divide_i16_by, divide_u16_by, divide_u32_by, parse_string};
+use crate::{
+    modbus::{ModbusRequest, ModbusResponse},
+    serde_helpers::{divide_i16_by, divide_u16_by, divide_u32_by, parse_string},
+};
+use bytes::{Buf, BufMut, Bytes};
 use macaddr::MacAddr6;
+use num_traits::FromPrimitive;
+use tracing::{debug, warn};
 
 #[derive(Primitive, Debug, Clone, Copy)]
 pub enum SofarMessageType {
@@ -8,8 +14,13 @@ pub enum SofarMessageType {
     Hello = 0x4110,
     HelloCd = 0x4810,
     Unknown44 = 0x4310,
+    ModbusRequest = 0x4510,
+    ModbusResponse = 0x1510,
 }
 
+/// Server responses use the control code of the request lowered by this offset.
+const RESPONSE_CONTROL_CODE_OFFSET: u16 = 0x3000;
+
 #[allow(dead_code)]
 #[derive(serde::Serialize, Debug)]
 pub struct ServerResponse {
@@ -43,27 +54,27 @@ pub struct Data {
     #[serde(deserialize_with = "divide_i16_by::<_, 10>")]
     pub inverter_temperature: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    vdc_1: f32,
+    pub vdc_1: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    vdc_2: f32,
+    pub vdc_2: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    idc_1: f32,
+    pub idc_1: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    idc_2: f32,
+    pub idc_2: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    iac_1: f32,
+    pub iac_1: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    iac_2: f32,
+    pub iac_2: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    iac_3: f32,
+    pub iac_3: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    vac_1: f32,
+    pub vac_1: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    vac_2: f32,
+    pub vac_2: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    vac_3: f32,
+    pub vac_3: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 100>")]
-    fac: f32,
+    pub fac: f32,
     pub current_power: u32,
     #[serde(deserialize_with = "divide_u32_by::<_, 100>")]
     pub daily_energy: f64,
@@ -81,32 +92,32 @@ pub struct Data {
     fault_code_8: u8,
     fault_code_9: u8,
     fault_code_10: u8,
-    alert_message_code: u16,
-    inner_board_message_code: u16,
+    pub alert_message_code: u16,
+    pub inner_board_message_code: u16,
     #[serde(deserialize_with = "parse_string::<_, 4>")]
     pub inverter_firmware: String,
     #[serde(deserialize_with = "parse_string::<_, 4>")]
     pub hardware_version: String,
     pub logger_temperature: i16,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    bus_voltage: f32,
+    pub bus_voltage: f32,
     #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
-    vice_cpu_input_voltage: f32,
+    pub vice_cpu_input_voltage: f32,
     #[serde(skip_serializing)]
     _unknown3: u16,
-    countdown_time: u16,
+    pub countdown_time: u16,
     #[serde(skip_serializing)]
     _unknown4: u16,
-    pv1_insulation_resistance: u16,
-    pv2_insulation_resistance: u16,
-    insulation_impedance: u16,
+    pub pv1_insulation_resistance: u16,
+    pub pv2_insulation_resistance: u16,
+    pub insulation_impedance: u16,
     pub country_code: u16,
     #[serde(skip_serializing)]
     _unknown5: u32,
-    leaking_current: u16,
-    a_phase_dc_distribution: u16,
-    b_phase_dc_distribution: u16,
-    c_phase_dc_distribution: u16,
+    pub leaking_current: u16,
+    pub a_phase_dc_distribution: u16,
+    pub b_phase_dc_distribution: u16,
+    pub c_phase_dc_distribution: u16,
     #[serde(deserialize_with = "parse_string::<_, 4>")]
     pub main_inverter_firmware: String,
     #[serde(deserialize_with = "parse_string::<_, 4>")]
@@ -119,6 +130,143 @@ pub struct Data {
     pub second: u8,
     #[serde(skip_serializing)]
     _unknown6: u32,
+    /// Trailing block of hybrid inverter frames, parsed separately
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub hybrid: Option<HybridData>,
+}
+
+/// Battery and grid meter block appended to the data of hybrid inverters
+/// (HYD-ES, ME3000).
+///
+/// Power values are positive when charging the battery and importing from
+/// the grid.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct HybridData {
+    #[serde(deserialize_with = "divide_u16_by::<_, 10>")]
+    pub battery_voltage: f32,
+    #[serde(deserialize_with = "divide_i16_by::<_, 100>")]
+    pub battery_current: f32,
+    pub battery_power: i32,
+    pub battery_soc: u16,
+    pub battery_soh: u16,
+    #[serde(deserialize_with = "divide_i16_by::<_, 10>")]
+    pub battery_temperature: f32,
+    pub grid_power: i32,
+    pub load_power: u32,
+    #[serde(deserialize_with = "divide_u32_by::<_, 10>")]
+    pub grid_import_energy: f64,
+    #[serde(deserialize_with = "divide_u32_by::<_, 10>")]
+    pub grid_export_energy: f64,
+    #[serde(deserialize_with = "divide_u32_by::<_, 10>")]
+    pub battery_charge_energy: f64,
+    #[serde(deserialize_with = "divide_u32_by::<_, 10>")]
+    pub battery_discharge_energy: f64,
+}
+
+/// Number of grid phases the inverter feeds.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phases {
+    Single,
+    Three,
+}
+
+impl Phases {
+    pub fn count(&self) -> usize {
+        match self {
+            Phases::Single => 1,
+            Phases::Three => 3,
+        }
+    }
+}
+
+/// Layouts of the data frame payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataLayout {
+    /// Single phase and three phase grid-tied inverters (KTL, TL-G3)
+    GridTied,
+    /// Grid-tied layout followed by [`HybridData`]
+    Hybrid,
+}
+
+impl DataLayout {
+    const GRID_TIED_LENGTH: usize = 151;
+    const HYBRID_LENGTH: usize = Self::GRID_TIED_LENGTH + 38;
+    /// Sensor types reported by loggers attached to grid-tied inverters.
+    const GRID_TIED_SENSOR_TYPES: [u16; 1] = [0x2701];
+    /// Sensor types reported by loggers attached to hybrid inverters.
+    const HYBRID_SENSOR_TYPES: [u16; 1] = [0x2702];
+
+    /// Selects layout by the sensor type list of the logger.
+    ///
+    /// Sensor types not seen yet are decoded as grid-tied, the layout shared
+    /// by all inverters, rather than guessing what follows it.
+    pub fn detect(sensor_type_list: u16, length: usize) -> anyhow::Result<Self> {
+        if length < Self::GRID_TIED_LENGTH {
+            return Err(anyhow::anyhow!("Data frame too short ({length} bytes)"));
+        }
+
+        if Self::HYBRID_SENSOR_TYPES.contains(&sensor_type_list) {
+            if length < Self::HYBRID_LENGTH {
+                return Err(anyhow::anyhow!(
+                    "Hybrid data frame too short ({length} bytes)"
+                ));
+            }
+            Ok(DataLayout::Hybrid)
+        } else {
+            if !Self::GRID_TIED_SENSOR_TYPES.contains(&sensor_type_list)
+                && length != Self::GRID_TIED_LENGTH
+            {
+                warn!(
+                    "Unknown sensor types {sensor_type_list:#06x} with {length} bytes of data, \
+                     decoding grid-tied values only"
+                );
+            }
+            Ok(DataLayout::GridTied)
+        }
+    }
+}
+
+impl Data {
+    /// Single-phase inverters leave voltages of the other phases at zero.
+    pub fn phases(&self) -> Phases {
+        if self.vac_2 > 0.0 || self.vac_3 > 0.0 {
+            Phases::Three
+        } else {
+            Phases::Single
+        }
+    }
+
+    /// Parses the data frame payload of `length` bytes.
+    pub fn from_bytes(buf: &[u8], length: usize) -> anyhow::Result<Self> {
+        let mut data: Data = bincode::deserialize(buf)?;
+        let layout = DataLayout::detect(data.sensor_type_list, length)?;
+        debug!(
+            "Detected {layout:?} data layout (sensor types {:#06x}, {length} bytes)",
+            data.sensor_type_list
+        );
+
+        if layout == DataLayout::Hybrid {
+            data.hybrid = Some(bincode::deserialize(&buf[DataLayout::GRID_TIED_LENGTH..])?);
+        }
+
+        Ok(data)
+    }
+
+    pub fn fault_codes(&self) -> [u8; 10] {
+        [
+            self.fault_code_1,
+            self.fault_code_2,
+            self.fault_code_3,
+            self.fault_code_4,
+            self.fault_code_5,
+            self.fault_code_6,
+            self.fault_code_7,
+            self.fault_code_8,
+            self.fault_code_9,
+            self.fault_code_10,
+        ]
+    }
 }
 
 #[allow(dead_code)]
@@ -130,15 +278,15 @@ pub struct Hello {
     timer: u32,
     #[serde(skip_serializing)]
     _unknown1: u32,
-    uploading_frequency: u8,
+    pub uploading_frequency: u8,
     data_logging_frequency: u8,
     hearbeat_frequency: u8,
     max_num_of_connected_devices: u8,
-    signal_quality: u8,
+    pub signal_quality: u8,
     sensor_type: u8,
     #[serde(deserialize_with = "parse_string::<_, 40>")]
     pub module_version: String,
-    sta_mac_address: MacAddr6,
+    pub sta_mac_address: MacAddr6,
     #[serde(deserialize_with = "parse_string::<_, 16>")]
     pub local_ip_address: String,
     #[serde(skip_serializing)]
@@ -210,34 +358,118 @@ pub struct Unknown44 {
     timestamp: u32,
     _unknown10: u16,
     #[serde(deserialize_with = "parse_string::<_, 16>")]
-    wifi_ssid: String,
+    pub wifi_ssid: String,
+}
+
+/// Modbus RTU frame wrapped for transport to the inverter.
+#[derive(Debug)]
+pub struct ModbusRequestData {
+    pub frame_type: u8,
+    pub sensor_type: u16,
+    pub total_working_time: u32,
+    pub power_on_time: u32,
+    pub offset_time: u32,
+    pub modbus_frame: Vec<u8>,
+}
+
+impl ModbusRequestData {
+    pub fn new(request: &ModbusRequest) -> Self {
+        ModbusRequestData {
+            frame_type: 0x02,
+            sensor_type: 0,
+            total_working_time: 0,
+            power_on_time: 0,
+            offset_time: 0,
+            modbus_frame: request.to_rtu(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(15 + self.modbus_frame.len());
+        buf.put_u8(self.frame_type);
+        buf.put_u16_le(self.sensor_type);
+        buf.put_u32_le(self.total_working_time);
+        buf.put_u32_le(self.power_on_time);
+        buf.put_u32_le(self.offset_time);
+        buf.extend_from_slice(&self.modbus_frame);
+        buf
+    }
+}
+
+/// Modbus RTU response of the inverter, as forwarded by the data logger.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ModbusResponseData {
+    pub frame_type: u8,
+    pub status: u8,
+    total_working_time: u32,
+    power_on_time: u32,
+    offset_time: u32,
+    pub modbus_frame: Vec<u8>,
+}
+
+impl ModbusResponseData {
+    pub fn from_bytes(mut buf: &[u8]) -> anyhow::Result<Self> {
+        if buf.len() < 14 {
+            return Err(anyhow::anyhow!(
+                "Modbus response too short ({} bytes)",
+                buf.len()
+            ));
+        }
+
+        Ok(ModbusResponseData {
+            frame_type: buf.get_u8(),
+            status: buf.get_u8(),
+            total_working_time: buf.get_u32_le(),
+            power_on_time: buf.get_u32_le(),
+            offset_time: buf.get_u32_le(),
+            modbus_frame: buf.to_vec(),
+        })
+    }
+
+    pub fn response(&self) -> anyhow::Result<ModbusResponse> {
+        ModbusResponse::from_rtu(&self.modbus_frame)
+    }
 }
 
 #[derive(Debug)]
 pub enum IncomingMessageData {
     Heartbeat(Heartbeat),
-    Data(Data),
+    Data(Box<Data>),
     Hello(Hello),
     HelloCd(HelloCd),
     #[allow(dead_code)]
     HelloEnd(HelloEnd),
     Unknown44(Unknown44),
+    ModbusResponse(ModbusResponseData),
+    /// Frame with a control code not known yet, kept raw for reverse-engineering.
+    Unknown {
+        control_code: u16,
+        payload: Bytes,
+    },
 }
 
 #[derive(Debug)]
 pub enum OutgoingMessageData {
     ServerResponse(ServerResponse),
+    ModbusRequest(ModbusRequestData),
 }
 
 #[derive(Debug)]
 pub struct SofarMessage<T> {
     pub data: T,
-    pub message_type: SofarMessageType,
+    pub control_code: u16,
     pub message_number: u8,
     pub message_number_2: u8,
     pub data_logger_sn: u32,
 }
 
+impl<T> SofarMessage<T> {
+    pub fn message_type(&self) -> Option<SofarMessageType> {
+        SofarMessageType::from_u16(self.control_code)
+    }
+}
+
 impl SofarMessage<OutgoingMessageData> {
     pub fn from_incoming_message(
         request: &SofarMessage<IncomingMessageData>,
@@ -250,6 +482,8 @@ impl SofarMessage<OutgoingMessageData> {
             IncomingMessageData::HelloCd(data) => data.one,
             IncomingMessageData::HelloEnd(data) => data.one,
             IncomingMessageData::Unknown44(data) => data._unknown1,
+            IncomingMessageData::ModbusResponse(data) => data.frame_type,
+            IncomingMessageData::Unknown { payload, .. } => payload.first().copied().unwrap_or(0),
         };
 
         SofarMessage {
@@ -260,10 +494,78 @@ impl SofarMessage<OutgoingMessageData> {
                 _unknown2: 0x0078,
                 _unknown3: 0,
             }),
-            message_type: request.message_type,
-            message_number: request.message_number + 1,
+            control_code: request
+                .control_code
+                .wrapping_sub(RESPONSE_CONTROL_CODE_OFFSET),
+            message_number: request.message_number.wrapping_add(1),
             message_number_2: request.message_number_2,
             data_logger_sn: request.data_logger_sn,
         }
     }
 }
+
+impl SofarMessage<OutgoingMessageData> {
+    /// Wraps Modbus request for the inverter connected to given data logger.
+    pub fn modbus_request(data_logger_sn: u32, sequence: u8, request: &ModbusRequest) -> Self {
+        SofarMessage {
+            data: OutgoingMessageData::ModbusRequest(ModbusRequestData::new(request)),
+            control_code: SofarMessageType::ModbusRequest as u16,
+            message_number: sequence,
+            message_number_2: 0,
+            data_logger_sn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+
+    use super::{Data, DataLayout};
+
+    /// Data payload with hybrid values following the grid-tied layout.
+    fn hybrid_payload(sensor_type_list: u16) -> Vec<u8> {
+        let mut payload = vec![0; DataLayout::GRID_TIED_LENGTH];
+        payload[1..3].copy_from_slice(&sensor_type_list.to_le_bytes());
+        payload.put_u16_le(5120);
+        payload.put_i16_le(-1250);
+        payload.put_i32_le(-640);
+        payload.put_u16_le(87);
+        payload.put_u16_le(98);
+        payload.put_i16_le(253);
+        payload.put_i32_le(-1200);
+        payload.put_u32_le(950);
+        payload.put_u32_le(12345);
+        payload.put_u32_le(23456);
+        payload.put_u32_le(3456);
+        payload.put_u32_le(4567);
+        payload
+    }
+
+    #[test]
+    fn hybrid_data() {
+        let payload = hybrid_payload(DataLayout::HYBRID_SENSOR_TYPES[0]);
+        let data = Data::from_bytes(&payload, payload.len()).unwrap();
+
+        let hybrid = data.hybrid.as_ref().unwrap();
+        assert_eq!(hybrid.battery_voltage, 512.0);
+        assert_eq!(hybrid.battery_soc, 87);
+        assert_eq!(hybrid.grid_export_energy, 2345.6);
+
+        // served by the REST API and kept in the state file
+        let json = serde_json::to_value(&data).unwrap();
+        assert_eq!(json["hybrid"]["battery_soc"], 87);
+        assert_eq!(json["hybrid"]["grid_power"], -1200);
+    }
+
+    #[test]
+    fn unknown_sensor_types_are_grid_tied() {
+        let payload = hybrid_payload(0);
+        let data = Data::from_bytes(&payload, payload.len()).unwrap();
+        assert!(data.hybrid.is_none());
+
+        let payload =
+            &hybrid_payload(DataLayout::HYBRID_SENSOR_TYPES[0])[..DataLayout::GRID_TIED_LENGTH];
+        assert!(Data::from_bytes(payload, payload.len()).is_err());
+    }
+}
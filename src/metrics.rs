@@ -0,0 +1,258 @@
+use crate::{
+    homeassistant::{entities_from_data, EntityType},
+    messages::Data,
+    sink::{InverterInfo, Sink},
+};
+use async_trait::async_trait;
+use prometheus::{
+    Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::error;
+
+const NAMESPACE: &str = "sofar";
+const READING_LABELS: [&str; 2] = ["inverter", "logger"];
+
+/// Prometheus metrics of the bridge and the latest inverter readings.
+///
+/// Cloning is cheap, all clones update the same registry.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    frames: IntCounterVec,
+    corrupt_frames: IntCounter,
+    dropped_bytes: IntCounter,
+    connections: IntGauge,
+    mqtt_publish_failures: IntCounter,
+    logger_last_seen: GaugeVec,
+    inverter_last_seen: GaugeVec,
+    /// Gauges of inverter readings, created on first use per entity
+    readings: Arc<Mutex<HashMap<String, GaugeVec>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+
+        let frames = IntCounterVec::new(
+            opts("frames_total", "Frames decoded, by message type"),
+            &["message_type"],
+        )
+        .unwrap();
+        let corrupt_frames = IntCounter::with_opts(opts(
+            "corrupt_frames_total",
+            "Frames dropped due to invalid length, end marker, checksum or payload",
+        ))
+        .unwrap();
+        let dropped_bytes = IntCounter::with_opts(opts(
+            "dropped_bytes_total",
+            "Bytes discarded while looking for a valid frame",
+        ))
+        .unwrap();
+        let connections =
+            IntGauge::with_opts(opts("connections", "Active data logger connections")).unwrap();
+        let mqtt_publish_failures = IntCounter::with_opts(opts(
+            "mqtt_publish_failures_total",
+            "Messages that could not be handed to the MQTT client",
+        ))
+        .unwrap();
+        let logger_last_seen = GaugeVec::new(
+            opts(
+                "logger_last_seen_timestamp_seconds",
+                "Time of the last frame received from the data logger",
+            ),
+            &["logger"],
+        )
+        .unwrap();
+        let inverter_last_seen = GaugeVec::new(
+            opts(
+                "inverter_last_seen_timestamp_seconds",
+                "Time of the last data frame received for the inverter",
+            ),
+            &READING_LABELS,
+        )
+        .unwrap();
+
+        registry.register(Box::new(frames.clone())).unwrap();
+        registry.register(Box::new(corrupt_frames.clone())).unwrap();
+        registry.register(Box::new(dropped_bytes.clone())).unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry
+            .register(Box::new(mqtt_publish_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(logger_last_seen.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(inverter_last_seen.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            frames,
+            corrupt_frames,
+            dropped_bytes,
+            connections,
+            mqtt_publish_failures,
+            logger_last_seen,
+            inverter_last_seen,
+            readings: Arc::default(),
+        }
+    }
+
+    pub fn frame_decoded(&self, message_type: &str, data_logger_sn: u32) {
+        self.frames.with_label_values(&[message_type]).inc();
+        self.logger_last_seen
+            .with_label_values(&[&data_logger_sn.to_string()])
+            .set(now());
+    }
+
+    /// Adds frames and bytes the codec dropped since the last call.
+    pub fn frames_dropped(&self, frames: u64, bytes: u64) {
+        self.corrupt_frames.inc_by(frames);
+        self.dropped_bytes.inc_by(bytes);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.inc();
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.dec();
+    }
+
+    pub fn connections(&self) -> i64 {
+        self.connections.get()
+    }
+
+    pub fn mqtt_publish_failed(&self) {
+        self.mqtt_publish_failures.inc();
+    }
+
+    /// Exports numeric entities as `sofar_<entity name>` gauges.
+    pub fn record_readings(&self, inverter: &str, data_logger_sn: u32, entities: &[EntityType]) {
+        let labels = [inverter, &data_logger_sn.to_string()];
+        let mut readings = self.readings.lock().unwrap();
+
+        self.inverter_last_seen
+            .with_label_values(&labels)
+            .set(now());
+
+        for entity in entities {
+            let Some(value) = entity.numeric_value() else {
+                continue;
+            };
+
+            let gauge = match readings.get(entity.name()) {
+                Some(gauge) => gauge,
+                None => {
+                    let gauge = GaugeVec::new(
+                        Opts::new(entity.name(), format!("Inverter reading {}", entity.name()))
+                            .namespace(NAMESPACE),
+                        &READING_LABELS,
+                    )
+                    .unwrap();
+
+                    if let Err(err) = self.registry.register(Box::new(gauge.clone())) {
+                        error!("Error registering metric {} ({err})", entity.name());
+                        continue;
+                    }
+                    readings.entry(entity.name().to_owned()).or_insert(gauge)
+                }
+            };
+
+            gauge.with_label_values(&labels).set(value);
+        }
+    }
+
+    /// Renders all metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Error encoding metrics ({err})");
+        }
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Exports every decoded value, `entities` are skipped as inverter overrides
+/// may exclude or correct them.
+#[async_trait]
+impl Sink for Metrics {
+    async fn on_data(
+        &self,
+        inverter: &InverterInfo,
+        data: &Data,
+        _entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        let entities = entities_from_data(data, data.phases());
+        self.record_readings(&inverter.serial, inverter.data_logger_sn, &entities);
+        Ok(())
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::{
+        codec::DATA,
+        homeassistant::EntityType,
+        messages::Data,
+        sink::{tests::inverter, Sink},
+    };
+
+    #[test]
+    fn renders_readings_and_counters() {
+        let metrics = Metrics::new();
+
+        metrics.frame_decoded("Data", 1744743503);
+        metrics.record_readings(
+            "sf4es003m4c058",
+            1744743503,
+            &[
+                EntityType::PowerSensor {
+                    name: "current_power".to_string(),
+                    value: 310,
+                },
+                EntityType::ProblemSensor {
+                    name: "inverter_fault".to_string(),
+                    value: false,
+                },
+            ],
+        );
+
+        let output = metrics.render();
+
+        assert!(output.contains(r#"sofar_frames_total{message_type="Data"} 1"#));
+        assert!(output
+            .contains(r#"sofar_current_power{inverter="sf4es003m4c058",logger="1744743503"} 310"#));
+        assert!(output
+            .contains(r#"sofar_inverter_fault{inverter="sf4es003m4c058",logger="1744743503"} 0"#));
+    }
+
+    #[tokio::test]
+    async fn records_values_before_overrides() {
+        let metrics = Metrics::new();
+        let data = Data::from_bytes(&DATA[11..DATA.len() - 2], DATA.len() - 13).unwrap();
+
+        // overrides removed every entity
+        metrics.on_data(&inverter(), &data, &[]).await.unwrap();
+
+        assert!(metrics
+            .render()
+            .contains(r#"sofar_current_power{inverter="sf4es003m4c058",logger="1744743503"} 310"#));
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::anyhow;
+use bytes::{Buf, BufMut};
+use num_traits::FromPrimitive;
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModbusFunction {
+    ReadHoldingRegisters = 0x03,
+    ReadInputRegisters = 0x04,
+    WriteSingleRegister = 0x06,
+    WriteMultipleRegisters = 0x10,
+}
+
+/// Modbus RTU request sent to the inverter through the data logger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModbusRequest {
+    pub slave_id: u8,
+    pub function: ModbusFunction,
+    pub address: u16,
+    pub values: Vec<u16>,
+    pub count: u16,
+}
+
+#[allow(dead_code)]
+impl ModbusRequest {
+    pub fn read_holding_registers(slave_id: u8, address: u16, count: u16) -> Self {
+        ModbusRequest {
+            slave_id,
+            function: ModbusFunction::ReadHoldingRegisters,
+            address,
+            values: vec![],
+            count,
+        }
+    }
+
+    pub fn read_input_registers(slave_id: u8, address: u16, count: u16) -> Self {
+        ModbusRequest {
+            slave_id,
+            function: ModbusFunction::ReadInputRegisters,
+            address,
+            values: vec![],
+            count,
+        }
+    }
+
+    pub fn write_single_register(slave_id: u8, address: u16, value: u16) -> Self {
+        ModbusRequest {
+            slave_id,
+            function: ModbusFunction::WriteSingleRegister,
+            address,
+            values: vec![value],
+            count: 1,
+        }
+    }
+
+    pub fn write_multiple_registers(slave_id: u8, address: u16, values: &[u16]) -> Self {
+        ModbusRequest {
+            slave_id,
+            function: ModbusFunction::WriteMultipleRegisters,
+            address,
+            values: values.to_vec(),
+            count: values.len() as u16,
+        }
+    }
+
+    /// Serializes the request into a RTU frame, including trailing CRC.
+    pub fn to_rtu(&self) -> Vec<u8> {
+        let mut frame = vec![self.slave_id, self.function as u8];
+        frame.put_u16(self.address);
+
+        match self.function {
+            ModbusFunction::ReadHoldingRegisters | ModbusFunction::ReadInputRegisters => {
+                frame.put_u16(self.count);
+            }
+            ModbusFunction::WriteSingleRegister => {
+                frame.put_u16(self.values[0]);
+            }
+            ModbusFunction::WriteMultipleRegisters => {
+                frame.put_u16(self.count);
+                frame.put_u8((self.values.len() * 2) as u8);
+                self.values.iter().for_each(|value| frame.put_u16(*value));
+            }
+        }
+
+        frame.put_u16_le(crc16(&frame));
+        frame
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModbusResponse {
+    Registers(Vec<u16>),
+    /// Echo of a write, `value` holds the register count for multiple writes.
+    Written {
+        address: u16,
+        value: u16,
+    },
+    Exception {
+        function: u8,
+        code: u8,
+    },
+}
+
+impl ModbusResponse {
+    /// Parses a RTU response frame, verifying its CRC.
+    ///
+    /// Some logger firmware appends padding after the frame, so the frame
+    /// length is derived from its contents instead of the buffer length.
+    pub fn from_rtu(frame: &[u8]) -> anyhow::Result<Self> {
+        if frame.len() < 5 {
+            return Err(anyhow!("Modbus frame too short ({} bytes)", frame.len()));
+        }
+
+        let function = frame[1];
+        let length = if function & 0x80 != 0 {
+            3
+        } else {
+            match ModbusFunction::from_u8(function) {
+                Some(ModbusFunction::ReadHoldingRegisters | ModbusFunction::ReadInputRegisters) => {
+                    3 + usize::from(frame[2])
+                }
+                Some(
+                    ModbusFunction::WriteSingleRegister | ModbusFunction::WriteMultipleRegisters,
+                ) => 6,
+                None => return Err(anyhow!("Unknown Modbus function {function}")),
+            }
+        };
+
+        if frame.len() < length + 2 {
+            return Err(anyhow!("Modbus frame too short ({} bytes)", frame.len()));
+        }
+
+        let mut crc_bytes = &frame[length..length + 2];
+        let crc = crc_bytes.get_u16_le();
+        if crc != crc16(&frame[..length]) {
+            return Err(anyhow!("Invalid Modbus CRC {crc:#06x}"));
+        }
+
+        let mut data = &frame[2..length];
+
+        Ok(if function & 0x80 != 0 {
+            ModbusResponse::Exception {
+                function: function & 0x7f,
+                code: data.get_u8(),
+            }
+        } else {
+            match ModbusFunction::from_u8(function) {
+                Some(ModbusFunction::ReadHoldingRegisters | ModbusFunction::ReadInputRegisters) => {
+                    data.advance(1);
+                    ModbusResponse::Registers(
+                        data.chunks_exact(2)
+                            .map(|mut chunk| chunk.get_u16())
+                            .collect(),
+                    )
+                }
+                _ => ModbusResponse::Written {
+                    address: data.get_u16(),
+                    value: data.get_u16(),
+                },
+            }
+        })
+    }
+}
+
+/// CRC-16/MODBUS checksum.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, byte| {
+        (0..8).fold(crc ^ u16::from(*byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ModbusRequest, ModbusResponse};
+
+    #[test]
+    fn read_request() {
+        let request = ModbusRequest::read_holding_registers(1, 0x0000, 1);
+
+        assert_eq!(request.to_rtu(), vec![1, 3, 0, 0, 0, 1, 132, 10]);
+    }
+
+    #[test]
+    fn write_request() {
+        let request = ModbusRequest::write_single_register(1, 0x1104, 1);
+
+        assert_eq!(request.to_rtu(), vec![1, 6, 17, 4, 0, 1, 12, 247]);
+    }
+
+    #[test]
+    fn read_response_with_padding() {
+        let response = ModbusResponse::from_rtu(&[1, 3, 2, 0, 42, 57, 155, 0, 0]).unwrap();
+
+        assert_eq!(response, ModbusResponse::Registers(vec![42]));
+    }
+
+    #[test]
+    fn invalid_crc() {
+        assert!(ModbusResponse::from_rtu(&[1, 3, 2, 0, 42, 57, 156]).is_err());
+    }
+}
@@ -1,131 +1,587 @@
 use crate::{
+    commands::CommandRouter,
     config::{Config, MQTT_CLIENT_ID},
-    homeassistant::{Device, Entity, EntityType},
+    homeassistant::{Attributes, Availability, Device, Entity, EntityType},
+    messages::Data,
+    metrics::Metrics,
+    sink::{InverterInfo, LoggerInfo, Sink},
+    tls::tls_configuration,
 };
 use anyhow::Context;
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use async_trait::async_trait;
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS,
+    Transport,
+};
 use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{error, info, warn};
 
+/// Maximum number of requests buffered between publishers and the event loop.
+const REQUESTS_CAPACITY: usize = 100;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Handle to the shared MQTT connection.
+///
+/// Cloning is cheap, every clone hands its publishes to the same event loop
+/// through the client's request channel.
+#[derive(Clone)]
 pub struct MqttPublisher {
-    pub mqtt_client: AsyncClient,
-    pub event_loop: EventLoop,
-    prefix: String,
+    mqtt_client: AsyncClient,
+    discovery_prefix: String,
+    topic_prefix: String,
+    bridge_prefix: String,
+    bridge_status_topic: String,
+    /// Topic prefixes subscribed for commands, with the inverter serial
+    command_prefixes: Arc<Mutex<HashMap<String, String>>>,
+    metrics: Metrics,
 }
 
 impl MqttPublisher {
-    pub(crate) fn new(prefix: String) -> Self {
-        let config = serde_env::from_env::<Config>().unwrap();
-        let mut mqttoptions = MqttOptions::new(MQTT_CLIENT_ID, config.mqtt_host, config.mqtt_port);
+    pub(crate) fn new(config: &Config, metrics: Metrics) -> anyhow::Result<(Self, EventLoop)> {
+        let mut mqttoptions = MqttOptions::new(
+            MQTT_CLIENT_ID,
+            config.mqtt_host.to_owned(),
+            config.mqtt_port,
+        );
 
-        if let (Some(mqtt_user), Some(mqtt_password)) = (config.mqtt_user, config.mqtt_password) {
+        if let (Some(mqtt_user), Some(mqtt_password)) =
+            (config.mqtt_user.to_owned(), config.mqtt_password.to_owned())
+        {
             mqttoptions.set_credentials(mqtt_user, mqtt_password);
         }
 
-        let (mqtt_client, event_loop) = AsyncClient::new(mqttoptions.to_owned(), 10);
+        let bridge_status_topic = format!("{}/bridge/status", config.mqtt_bridge_prefix);
+        mqttoptions.set_last_will(LastWill::new(
+            &bridge_status_topic,
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        if config.mqtt_tls {
+            mqttoptions.set_transport(Transport::tls_with_config(tls_configuration(config)?));
+        }
+
+        let (mqtt_client, event_loop) = AsyncClient::new(mqttoptions, REQUESTS_CAPACITY);
 
-        MqttPublisher {
-            mqtt_client,
+        Ok((
+            MqttPublisher {
+                mqtt_client,
+                discovery_prefix: config.mqtt_discovery_prefix.to_owned(),
+                topic_prefix: config.mqtt_topic_prefix.to_owned(),
+                bridge_prefix: config.mqtt_bridge_prefix.to_owned(),
+                bridge_status_topic,
+                command_prefixes: Arc::default(),
+                metrics,
+            },
             event_loop,
-            prefix,
+        ))
+    }
+
+    /// Hands the message to the event loop, counting failures in metrics.
+    async fn publish(
+        &self,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        let result = self.mqtt_client.publish(topic, qos, retain, payload).await;
+
+        if result.is_err() {
+            self.metrics.mqtt_publish_failed();
         }
+        result
     }
 
-    pub async fn publish_state(&mut self, entity: &EntityType) -> anyhow::Result<()> {
-        let (payload, name) = match entity {
-            EntityType::EnergySensor { name, value } => (value.to_string(), name.to_string()),
-            EntityType::PowerSensor { name, value } => (value.to_string(), name.to_string()),
-            EntityType::TemperatureSensor { name, value } => (value.to_string(), name.to_string()),
-            EntityType::GenericSensor { name, value } => (value.to_string(), name.to_string()),
-            EntityType::GenericDiscreteSensor { name, value } => {
-                (value.to_string(), name.to_string())
-            }
-        };
+    /// Topic prefix of data logger diagnostics, under the bridge prefix.
+    pub fn logger_prefix(&self, data_logger_sn: u32) -> String {
+        format!("{}/logger/{data_logger_sn}", self.bridge_prefix)
+    }
 
-        self.mqtt_client
-            .publish(
-                format!("{}/state/{}", self.prefix, name),
-                QoS::AtMostOnce,
-                true,
-                payload,
-            )
-            .await?;
+    /// Expands the configured topic prefix template for given inverter.
+    pub fn topic_prefix(&self, serial: &str) -> String {
+        self.topic_prefix
+            .replace("{serial}", &serial.trim().to_lowercase())
+    }
 
-        self.event_loop.poll().await.with_context(|| {
-            format!(
-                "Error sending state for path: {}/state/{}",
-                self.prefix, name
-            )
-        })?;
+    pub async fn publish_state(&self, prefix: &str, entity: &EntityType) -> anyhow::Result<()> {
+        let name = entity.name();
+
+        self.publish(
+            format!("{prefix}/state/{name}"),
+            QoS::AtMostOnce,
+            true,
+            entity.state(),
+        )
+        .await
+        .with_context(|| format!("Error sending state for path: {prefix}/state/{name}"))?;
         Ok(())
     }
 
     pub async fn publish_discovery(
-        &mut self,
+        &self,
+        prefix: &str,
         entity: &EntityType,
         device: &Device,
     ) -> anyhow::Result<()> {
-        let (payload, name) = match entity {
-            EntityType::EnergySensor { name, .. } => (
-                Entity::energy_sensor(name.to_string(), self.prefix.to_owned(), device),
-                name.to_string(),
-            ),
-            EntityType::PowerSensor { name, .. } => (
-                Entity::power_sensor(name.to_string(), self.prefix.to_owned(), device),
-                name.to_string(),
-            ),
-            EntityType::TemperatureSensor { name, .. } => (
-                Entity::temperature_entity(name.to_string(), self.prefix.to_owned(), device),
-                name.to_string(),
-            ),
-            EntityType::GenericSensor { name, .. } => (
-                Entity::generic_sensor(name.to_string(), self.prefix.to_owned(), device, false),
-                name.to_string(),
-            ),
-            EntityType::GenericDiscreteSensor { name, .. } => (
-                Entity::generic_sensor(name.to_string(), self.prefix.to_owned(), device, true),
-                name.to_string(),
-            ),
-        };
-
-        self.mqtt_client
-            .publish(
-                format!("homeassistant/sensor/{}/{name}/config", device.identifiers),
-                QoS::AtMostOnce,
-                true,
-                serde_json::to_string(&payload)?,
-            )
-            .await?;
+        let mut payload = Entity::from_entity_type(entity, prefix.to_owned(), device);
+        payload.availability = vec![
+            Availability {
+                topic: self.bridge_status_topic.to_owned(),
+            },
+            Availability {
+                topic: format!("{prefix}/status"),
+            },
+        ];
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            self.discovery_prefix,
+            entity.component(),
+            device.identifiers,
+            entity.name()
+        );
 
-        self.event_loop.poll().await.with_context(|| {
-            format!(
-                "Error sending discovery for path: homeassistant/sensor/{}/{name}/config",
-                device.identifiers
-            )
-        })?;
+        self.publish(
+            &topic,
+            QoS::AtMostOnce,
+            true,
+            serde_json::to_string(&payload)?,
+        )
+        .await
+        .with_context(|| format!("Error sending discovery for path: {topic}"))?;
         Ok(())
     }
 
-    pub async fn publish_attributes(&mut self, value: &Value) -> anyhow::Result<()> {
+    pub async fn publish_attributes(&self, prefix: &str, value: &Value) -> anyhow::Result<()> {
         let payload = match value {
             Value::String(a) => a.trim().to_owned(),
             a => a.to_string(),
         };
 
+        self.publish(
+            format!("{prefix}/attributes"),
+            QoS::AtMostOnce,
+            true,
+            payload,
+        )
+        .await
+        .with_context(|| format!("Error sending attributes for path: {prefix}/attributes"))?;
+        Ok(())
+    }
+
+    /// Subscribes to command topics of the inverter, unless already done.
+    pub async fn subscribe_commands(&self, prefix: &str, serial: &str) -> anyhow::Result<()> {
+        if self
+            .command_prefixes
+            .lock()
+            .unwrap()
+            .insert(prefix.to_owned(), serial.to_owned())
+            .is_some()
+        {
+            return Ok(());
+        }
+
         self.mqtt_client
-            .publish(
-                format!("{}/attributes", self.prefix),
-                QoS::AtMostOnce,
-                true,
-                payload,
-            )
+            .subscribe(format!("{prefix}/set/+"), QoS::AtLeastOnce)
+            .await
+            .with_context(|| format!("Error subscribing to {prefix}/set/+"))?;
+        Ok(())
+    }
+
+    pub async fn publish_availability(&self, prefix: &str, online: bool) -> anyhow::Result<()> {
+        self.publish(
+            format!("{prefix}/status"),
+            QoS::AtLeastOnce,
+            true,
+            if online { ONLINE } else { OFFLINE },
+        )
+        .await
+        .with_context(|| format!("Error sending availability for path: {prefix}/status"))?;
+        Ok(())
+    }
+
+    /// Publishes hex encoded payload of a frame with unknown control code.
+    ///
+    /// Published under the bridge prefix, as such frames can arrive before
+    /// the inverter serial number is known.
+    pub async fn publish_unknown_frame(
+        &self,
+        data_logger_sn: u32,
+        control_code: u16,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let topic = format!(
+            "{}/unknown/{control_code:04x}",
+            self.logger_prefix(data_logger_sn)
+        );
+        let payload: String = payload.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        self.publish(&topic, QoS::AtMostOnce, false, payload)
+            .await
+            .with_context(|| format!("Error sending unknown frame for path: {topic}"))?;
+        Ok(())
+    }
+
+    /// Disconnects once all queued publishes are sent, ending the event loop.
+    ///
+    /// The broker does not send the last will on a clean disconnect, so the
+    /// offline bridge status is published first.
+    pub async fn disconnect(&self) -> anyhow::Result<()> {
+        self.publish(&self.bridge_status_topic, QoS::AtLeastOnce, true, OFFLINE)
+            .await
+            .context("Error sending bridge status")?;
+        self.mqtt_client
+            .disconnect()
+            .await
+            .context("Error disconnecting from MQTT broker")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for MqttPublisher {
+    /// Publishes attributes, discovery configuration and state of all entities.
+    async fn on_data(
+        &self,
+        inverter: &InverterInfo,
+        data: &Data,
+        entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        let prefix = self.topic_prefix(&inverter.serial);
+        let attributes = Attributes::from_data(data);
+
+        info!("Sending data to MQTT broker");
+        info!("Sending attributes ({:?})", attributes);
+
+        self.publish_attributes(&prefix, &serde_json::to_value(&attributes)?)
             .await?;
 
-        self.event_loop.poll().await.with_context(|| {
-            format!(
-                "Error sending attributes for path: {}/attributes",
-                self.prefix
-            )
-        })?;
+        info!("Sending data ({:?})", entities);
+
+        for entity in entities {
+            self.publish_discovery(&prefix, entity, &inverter.device)
+                .await?;
+            self.publish_state(&prefix, entity).await?;
+        }
+
+        self.subscribe_commands(&prefix, &inverter.serial).await
+    }
+
+    async fn on_setting(&self, inverter: &InverterInfo, entity: &EntityType) -> anyhow::Result<()> {
+        let prefix = self.topic_prefix(&inverter.serial);
+
+        self.publish_discovery(&prefix, entity, &inverter.device)
+            .await?;
+        self.publish_state(&prefix, entity).await
+    }
+
+    async fn on_unknown_frame(
+        &self,
+        data_logger_sn: u32,
+        control_code: u16,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        self.publish_unknown_frame(data_logger_sn, control_code, payload)
+            .await
+    }
+
+    async fn on_availability(&self, inverter: &InverterInfo, online: bool) -> anyhow::Result<()> {
+        self.publish_availability(&self.topic_prefix(&inverter.serial), online)
+            .await
+    }
+
+    async fn on_logger_data(
+        &self,
+        logger: &LoggerInfo,
+        entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        let prefix = self.logger_prefix(logger.data_logger_sn);
+
+        for entity in entities {
+            self.publish_discovery(&prefix, entity, &logger.device)
+                .await?;
+            self.publish_state(&prefix, entity).await?;
+        }
+        self.publish_availability(&prefix, true).await
+    }
+
+    async fn on_disconnect(
+        &self,
+        logger: &LoggerInfo,
+        inverter: Option<&InverterInfo>,
+    ) -> anyhow::Result<()> {
+        self.publish_availability(&self.logger_prefix(logger.data_logger_sn), false)
+            .await?;
+
+        if let Some(inverter) = inverter {
+            self.publish_availability(&self.topic_prefix(&inverter.serial), false)
+                .await?;
+        }
         Ok(())
     }
 }
+
+/// Drives the MQTT connection for the whole lifetime of the process.
+///
+/// `rumqttc` reconnects on the next poll after an error, so the loop only has
+/// to wait between attempts. The delay doubles on every consecutive failure
+/// and is reset once the broker acknowledges the connection.
+///
+/// Bridge status is published on every connection, the broker takes care of
+/// the offline state through the last will unless the bridge disconnects
+/// cleanly. Command subscriptions do not
+/// survive clean sessions, so they are renewed as well.
+///
+/// Returns after [`MqttPublisher::disconnect`] is sent to the broker.
+pub async fn run_event_loop(
+    mut event_loop: EventLoop,
+    publisher: MqttPublisher,
+    command_router: CommandRouter,
+) {
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                reconnect_delay = MIN_RECONNECT_DELAY;
+
+                // the event loop is not polled while awaiting, so never block here
+                if let Err(err) = publisher.mqtt_client.try_publish(
+                    &publisher.bridge_status_topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    ONLINE,
+                ) {
+                    publisher.metrics.mqtt_publish_failed();
+                    error!("Error sending bridge status ({err})");
+                }
+
+                let prefixes: Vec<_> = publisher
+                    .command_prefixes
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect();
+                for prefix in prefixes {
+                    if let Err(err) = publisher
+                        .mqtt_client
+                        .try_subscribe(format!("{prefix}/set/+"), QoS::AtLeastOnce)
+                    {
+                        error!("Error subscribing to {prefix}/set/+ ({err})");
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let Some((prefix, name)) = publish.topic.rsplit_once("/set/") else {
+                    continue;
+                };
+                let serial = publisher
+                    .command_prefixes
+                    .lock()
+                    .unwrap()
+                    .get(prefix)
+                    .cloned();
+
+                match serial {
+                    Some(serial) => command_router.route(
+                        &serial,
+                        name,
+                        &String::from_utf8_lossy(&publish.payload),
+                    ),
+                    None => warn!("Received command for unknown topic {}", publish.topic),
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                info!("Disconnected from MQTT broker");
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!(
+                    "MQTT connection error ({err}), reconnecting in {}s",
+                    reconnect_delay.as_secs()
+                );
+                tokio::time::sleep(reconnect_delay).await;
+                reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::Arc, time::Duration};
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use rumqttc::{
+        tokio_rustls::{
+            rustls::{
+                self, server::AllowAnyAuthenticatedClient, PrivateKey, RootCertStore, ServerConfig,
+            },
+            TlsAcceptor,
+        },
+        Event, EventLoop, Packet,
+    };
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use crate::{config::Config, metrics::Metrics, mqtt::MqttPublisher};
+
+    fn ca_certificate() -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn server_config(ca: &Certificate, client_roots: Option<RootCertStore>) -> ServerConfig {
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_roots {
+            Some(roots) => {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        builder
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    server.serialize_der_with_signer(ca).unwrap(),
+                )],
+                PrivateKey(server.serialize_private_key_der()),
+            )
+            .unwrap()
+    }
+
+    /// Accepts a single TLS connection and answers CONNECT with CONNACK.
+    async fn tls_broker(server_config: ServerConfig) -> (u16, JoinHandle<anyhow::Result<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let handle = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(socket).await?;
+            let packet_type = stream.read_u8().await?;
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await?;
+            stream.flush().await?;
+            Ok(packet_type)
+        });
+
+        (port, handle)
+    }
+
+    fn client_config(port: u16, extra: serde_json::Value) -> Config {
+        let mut value = json!({
+            "mqtt_host": "localhost",
+            "mqtt_port": port,
+            "mqtt_tls": true,
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn write_file(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    async fn poll_connack(event_loop: &mut EventLoop) -> anyhow::Result<()> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Event::Incoming(Packet::ConnAck(_)) = event_loop.poll().await? {
+                    return Ok(());
+                }
+            }
+        })
+        .await?
+    }
+
+    #[tokio::test]
+    async fn connects_with_custom_ca_and_client_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = ca_certificate();
+        let client = rcgen::generate_simple_self_signed(vec!["client".into()]).unwrap();
+
+        let mut client_roots = RootCertStore::empty();
+        client_roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let (port, broker) = tls_broker(server_config(&ca, Some(client_roots))).await;
+
+        let config = client_config(
+            port,
+            json!({
+                "mqtt_ca_file": write_file(dir.path(), "ca.pem", &ca.serialize_pem().unwrap()),
+                "mqtt_client_cert_file": write_file(
+                    dir.path(),
+                    "client.pem",
+                    &client.serialize_pem_with_signer(&ca).unwrap(),
+                ),
+                "mqtt_client_key_file": write_file(
+                    dir.path(),
+                    "client.key",
+                    &client.serialize_private_key_pem(),
+                ),
+            }),
+        );
+        let (_publisher, mut event_loop) = MqttPublisher::new(&config, Metrics::new()).unwrap();
+
+        poll_connack(&mut event_loop).await.unwrap();
+        // CONNECT packet type
+        assert_eq!(broker.await.unwrap().unwrap(), 0x10);
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_broker_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, _broker) = tls_broker(server_config(&ca_certificate(), None)).await;
+
+        let other_ca = ca_certificate().serialize_pem().unwrap();
+        let config = client_config(
+            port,
+            json!({ "mqtt_ca_file": write_file(dir.path(), "ca.pem", &other_ca) }),
+        );
+        let (_publisher, mut event_loop) = MqttPublisher::new(&config, Metrics::new()).unwrap();
+
+        assert!(poll_connack(&mut event_loop).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn insecure_mode_skips_broker_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, broker) = tls_broker(server_config(&ca_certificate(), None)).await;
+
+        let other_ca = ca_certificate().serialize_pem().unwrap();
+        let config = client_config(
+            port,
+            json!({
+                "mqtt_ca_file": write_file(dir.path(), "ca.pem", &other_ca),
+                "mqtt_tls_insecure": true,
+            }),
+        );
+        let (_publisher, mut event_loop) = MqttPublisher::new(&config, Metrics::new()).unwrap();
+
+        poll_connack(&mut event_loop).await.unwrap();
+        assert_eq!(broker.await.unwrap().unwrap(), 0x10);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{
+    sink::InverterInfo,
+    state::{now, HelloInfo, LoggerState},
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// Data loggers known to the bridge, keyed by the serial number from the
+/// frame header rather than by connection.
+///
+/// The LSW-3 reconnects often and only says hello after powering up, so
+/// hello, heartbeat and data details from any earlier connection are merged
+/// here. Only the latest connection of a data logger is kept open.
+///
+/// Cloning is cheap, all clones share the same loggers.
+#[derive(Clone, Default)]
+pub struct LoggerRegistry {
+    loggers: Arc<Mutex<HashMap<u32, LoggerEntry>>>,
+    next_connection_id: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct LoggerEntry {
+    hello: Option<HelloInfo>,
+    /// Unix time of the latest heartbeat frame
+    last_heartbeat: Option<u64>,
+    /// Inverter of the latest data frame
+    inverter: Option<InverterInfo>,
+    connection: Option<Connection>,
+}
+
+struct Connection {
+    id: u64,
+    peer: SocketAddr,
+    close: oneshot::Sender<()>,
+}
+
+/// Connection of a data logger attached to the registry.
+pub struct Attachment {
+    id: u64,
+    /// Completes when a newer connection of the same data logger replaces
+    /// this one
+    pub replaced: oneshot::Receiver<()>,
+}
+
+impl LoggerRegistry {
+    /// Seeds the registry with the last known state.
+    pub fn restore(&self, loggers: &HashMap<u32, LoggerState>) {
+        let mut entries = self.loggers.lock().unwrap();
+
+        for (data_logger_sn, state) in loggers {
+            entries.entry(*data_logger_sn).or_default().hello = state.hello.clone();
+        }
+    }
+
+    /// Latest hello frame of the data logger, from any connection.
+    pub fn hello(&self, data_logger_sn: u32) -> Option<HelloInfo> {
+        self.loggers
+            .lock()
+            .unwrap()
+            .get(&data_logger_sn)
+            .and_then(|entry| entry.hello.clone())
+    }
+
+    pub fn update_hello(&self, hello: HelloInfo) {
+        let data_logger_sn = hello.data_logger_sn;
+        self.loggers
+            .lock()
+            .unwrap()
+            .entry(data_logger_sn)
+            .or_default()
+            .hello = Some(hello);
+    }
+
+    pub fn update_heartbeat(&self, data_logger_sn: u32, timestamp: u64) {
+        self.loggers
+            .lock()
+            .unwrap()
+            .entry(data_logger_sn)
+            .or_default()
+            .last_heartbeat = Some(timestamp);
+    }
+
+    /// Inverter of the latest data frame of the data logger, from any
+    /// connection.
+    pub fn inverter(&self, data_logger_sn: u32) -> Option<InverterInfo> {
+        self.loggers
+            .lock()
+            .unwrap()
+            .get(&data_logger_sn)
+            .and_then(|entry| entry.inverter.clone())
+    }
+
+    pub fn update_inverter(&self, inverter: InverterInfo) {
+        let data_logger_sn = inverter.data_logger_sn;
+        self.loggers
+            .lock()
+            .unwrap()
+            .entry(data_logger_sn)
+            .or_default()
+            .inverter = Some(inverter);
+    }
+
+    /// Makes `peer` the connection of the data logger, closing the previous
+    /// one, which the data logger abandoned without closing.
+    pub fn attach(&self, data_logger_sn: u32, peer: SocketAddr) -> Attachment {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let (close, replaced) = oneshot::channel();
+        let mut loggers = self.loggers.lock().unwrap();
+        let entry = loggers.entry(data_logger_sn).or_default();
+
+        let previous = entry.connection.replace(Connection { id, peer, close });
+        if let Some(previous) = previous {
+            let last_heartbeat = entry.last_heartbeat.map_or_else(
+                || String::from("no heartbeat"),
+                |timestamp| format!("last heartbeat {}s ago", now().saturating_sub(timestamp)),
+            );
+            warn!(
+                "Data logger {data_logger_sn} reconnected from {peer}, \
+                 closing stale connection from {} ({last_heartbeat})",
+                previous.peer
+            );
+            // the previous connection may be finishing on its own
+            let _ = previous.close.send(());
+        }
+
+        Attachment { id, replaced }
+    }
+
+    /// Forgets the connection, unless it was already replaced.
+    pub fn detach(&self, data_logger_sn: u32, attachment: &Attachment) {
+        if let Some(entry) = self.loggers.lock().unwrap().get_mut(&data_logger_sn) {
+            if entry
+                .connection
+                .as_ref()
+                .is_some_and(|connection| connection.id == attachment.id)
+            {
+                entry.connection = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoggerRegistry;
+    use crate::sink::tests::inverter;
+
+    #[test]
+    fn replaces_stale_connections() {
+        let registry = LoggerRegistry::default();
+        let mut first = registry.attach(1744743503, "10.0.0.64:51234".parse().unwrap());
+        let second = registry.attach(1744743503, "10.0.0.64:51301".parse().unwrap());
+        let mut other = registry.attach(1744743504, "10.0.0.65:50002".parse().unwrap());
+
+        assert!(first.replaced.try_recv().is_ok());
+        assert!(other.replaced.try_recv().is_err());
+
+        // the stale connection finishing must not detach its replacement
+        registry.detach(1744743503, &first);
+        assert!(registry.loggers.lock().unwrap()[&1744743503]
+            .connection
+            .is_some());
+        registry.detach(1744743503, &second);
+        assert!(registry.loggers.lock().unwrap()[&1744743503]
+            .connection
+            .is_none());
+    }
+
+    #[test]
+    fn merges_details_of_all_connections() {
+        let registry = LoggerRegistry::default();
+        let first = registry.attach(1744743503, "10.0.0.64:51234".parse().unwrap());
+        registry.update_inverter(inverter());
+        registry.detach(1744743503, &first);
+
+        let second = registry.attach(1744743503, "10.0.0.64:51301".parse().unwrap());
+        registry.update_heartbeat(1744743503, 1684481932);
+        registry.detach(1744743503, &second);
+
+        let loggers = registry.loggers.lock().unwrap();
+        assert_eq!(loggers[&1744743503].last_heartbeat, Some(1684481932));
+        assert_eq!(
+            loggers[&1744743503].inverter.as_ref().unwrap().serial,
+            "sf4es003m4c058"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    homeassistant::{Device, EntityType},
+    messages::{Data, Hello},
+    state::HelloInfo,
+};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::error;
+
+/// Inverter the readings belong to, with configured overrides applied.
+#[derive(Clone)]
+pub struct InverterInfo {
+    /// Serial number, trimmed and lowercase
+    pub serial: String,
+    pub data_logger_sn: u32,
+    pub device: Device,
+}
+
+/// Data logger the connection belongs to.
+#[derive(Clone)]
+pub struct LoggerInfo {
+    pub data_logger_sn: u32,
+    pub device: Device,
+}
+
+impl LoggerInfo {
+    pub fn new(data_logger_sn: u32) -> Self {
+        LoggerInfo {
+            data_logger_sn,
+            device: Device {
+                configuration_url: None,
+                identifiers: format!("sofar_logger_{data_logger_sn}"),
+                manufacturer: String::from("Sofar"),
+                model: String::from("LSW-3"),
+                name: format!("Sofar logger {data_logger_sn}"),
+                sw_version: None,
+                suggested_area: None,
+                via_device: None,
+                connections: vec![],
+            },
+        }
+    }
+
+    /// Fills in device details reported in the hello frame.
+    pub fn apply_hello(&mut self, hello: &HelloInfo) {
+        self.device.sw_version = Some(hello.module_version.clone());
+        self.device.configuration_url = Some(hello.configuration_url());
+        self.device.connections = vec![(String::from("mac"), hello.mac_address.clone())];
+    }
+}
+
+/// Destination of decoded inverter readings.
+///
+/// Only [`Sink::on_data`] is required, other events are ignored by default.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Handles the hello frame the data logger sends after connecting.
+    async fn on_hello(&self, _logger: &LoggerInfo, _hello: &Hello) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles diagnostics of the data logger, e.g. signal strength.
+    async fn on_logger_data(
+        &self,
+        _logger: &LoggerInfo,
+        _entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles a data frame, `entities` are already filtered and corrected.
+    async fn on_data(
+        &self,
+        inverter: &InverterInfo,
+        data: &Data,
+        entities: &[EntityType],
+    ) -> anyhow::Result<()>;
+
+    /// Handles a setting read back from the inverter.
+    async fn on_setting(
+        &self,
+        _inverter: &InverterInfo,
+        _entity: &EntityType,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles a frame with a control code not supported yet.
+    async fn on_unknown_frame(
+        &self,
+        _data_logger_sn: u32,
+        _control_code: u16,
+        _payload: &[u8],
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles the inverter going offline after no frames were received for
+    /// a while, or coming back.
+    async fn on_availability(&self, _inverter: &InverterInfo, _online: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles the data logger connection closing, `inverter` is the one
+    /// last seen on the connection.
+    async fn on_disconnect(
+        &self,
+        _logger: &LoggerInfo,
+        _inverter: Option<&InverterInfo>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Writes readings still buffered, called on shutdown.
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Passes every event to all enabled sinks.
+///
+/// Errors are logged per sink, so a failing output never keeps the others
+/// from receiving readings or the data logger from receiving responses.
+#[derive(Clone, Default)]
+pub struct Sinks {
+    sinks: Vec<Arc<dyn Sink>>,
+}
+
+impl Sinks {
+    pub fn push(&mut self, sink: impl Sink + 'static) {
+        self.sinks.push(Arc::new(sink));
+    }
+}
+
+fn report(result: anyhow::Result<()>) {
+    if let Err(err) = result {
+        error!("{err:?}");
+    }
+}
+
+#[async_trait]
+impl Sink for Sinks {
+    async fn on_hello(&self, logger: &LoggerInfo, hello: &Hello) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.on_hello(logger, hello).await);
+        }
+        Ok(())
+    }
+
+    async fn on_logger_data(
+        &self,
+        logger: &LoggerInfo,
+        entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.on_logger_data(logger, entities).await);
+        }
+        Ok(())
+    }
+
+    async fn on_data(
+        &self,
+        inverter: &InverterInfo,
+        data: &Data,
+        entities: &[EntityType],
+    ) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.on_data(inverter, data, entities).await);
+        }
+        Ok(())
+    }
+
+    async fn on_setting(&self, inverter: &InverterInfo, entity: &EntityType) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.on_setting(inverter, entity).await);
+        }
+        Ok(())
+    }
+
+    async fn on_unknown_frame(
+        &self,
+        data_logger_sn: u32,
+        control_code: u16,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(
+                sink.on_unknown_frame(data_logger_sn, control_code, payload)
+                    .await,
+            );
+        }
+        Ok(())
+    }
+
+    async fn on_availability(&self, inverter: &InverterInfo, online: bool) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.on_availability(inverter, online).await);
+        }
+        Ok(())
+    }
+
+    async fn on_disconnect(
+        &self,
+        logger: &LoggerInfo,
+        inverter: Option<&InverterInfo>,
+    ) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.on_disconnect(logger, inverter).await);
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        for sink in &self.sinks {
+            report(sink.flush().await);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::InverterInfo;
+    use crate::homeassistant::Device;
+
+    /// Inverter of the sample data frame, as described by the connection
+    /// handler.
+    pub(crate) fn inverter() -> InverterInfo {
+        InverterInfo {
+            serial: "sf4es003m4c058".to_string(),
+            data_logger_sn: 1744743503,
+            device: Device {
+                configuration_url: None,
+                identifiers: "sofar_sf4es003m4c058".to_string(),
+                manufacturer: "Sofar".to_string(),
+                model: "SF4ES003M4C058".to_string(),
+                name: "Sofar SF4ES003M4C058".to_string(),
+                sw_version: None,
+                suggested_area: None,
+                via_device: None,
+                connections: vec![],
+            },
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{
+    energy::EnergyCounters,
+    homeassistant::EntityType,
+    messages::{Data, Hello},
+    sink::InverterInfo,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::Mutex};
+use tracing::{error, info};
+
+/// Details of the data logger from its last hello frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HelloInfo {
+    pub data_logger_sn: u32,
+    pub module_version: String,
+    pub mac_address: String,
+    pub ip_address: String,
+    pub signal_quality: u8,
+    pub upload_interval: u8,
+    pub last_seen: u64,
+}
+
+impl HelloInfo {
+    pub fn new(data_logger_sn: u32, hello: &Hello) -> Self {
+        HelloInfo {
+            data_logger_sn,
+            module_version: hello.module_version.trim_matches(char::from(0)).to_string(),
+            mac_address: hello.sta_mac_address.to_string(),
+            ip_address: hello
+                .local_ip_address
+                .trim_matches(char::from(0))
+                .to_string(),
+            signal_quality: hello.signal_quality,
+            upload_interval: hello.uploading_frequency,
+            last_seen: now(),
+        }
+    }
+
+    /// Web interface of the data logger.
+    pub fn configuration_url(&self) -> String {
+        format!("http://{}/index_cn.html", self.ip_address)
+    }
+}
+
+/// Readings of the inverter from its last data frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InverterSnapshot {
+    pub serial: String,
+    pub data_logger_sn: u32,
+    pub name: String,
+    pub last_seen: u64,
+    pub data: Value,
+}
+
+impl InverterSnapshot {
+    pub fn new(inverter: &InverterInfo, data: &Data) -> anyhow::Result<Self> {
+        Ok(InverterSnapshot {
+            serial: inverter.serial.clone(),
+            data_logger_sn: inverter.data_logger_sn,
+            name: inverter.device.name.clone(),
+            last_seen: now(),
+            data: serde_json::to_value(data)?,
+        })
+    }
+}
+
+/// Last known state of a data logger and its inverter.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LoggerState {
+    #[serde(default)]
+    pub hello: Option<HelloInfo>,
+    #[serde(default)]
+    pub inverter: Option<InverterSnapshot>,
+    /// Energy totals by inverter serial
+    #[serde(default)]
+    pub energy: HashMap<String, EnergyCounters>,
+}
+
+/// State of every data logger seen, keyed by data logger serial number and
+/// optionally persisted, so restarts and reconnects start from the last known
+/// state instead of from scratch.
+///
+/// Cloning is cheap, all clones share the same state.
+#[derive(Clone)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    loggers: Arc<Mutex<HashMap<u32, LoggerState>>>,
+}
+
+impl StateStore {
+    /// Loads state from `path`, starting from scratch when it does not exist.
+    pub async fn load(path: Option<impl AsRef<Path>>) -> anyhow::Result<Self> {
+        let path = path.map(|path| path.as_ref().to_owned());
+        let mut loggers = HashMap::new();
+
+        if let Some(path) = &path {
+            match fs::read(path).await {
+                Ok(content) => {
+                    loggers = serde_json::from_slice(&content)
+                        .with_context(|| format!("Invalid state file {}", path.display()))?;
+                    info!("Loaded state of {} data loggers", loggers.len());
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Error reading state file {}", path.display()))
+                }
+            }
+        }
+
+        Ok(StateStore {
+            path,
+            loggers: Arc::new(Mutex::new(loggers)),
+        })
+    }
+
+    /// Last known state of all data loggers.
+    pub async fn all(&self) -> HashMap<u32, LoggerState> {
+        self.loggers.lock().await.clone()
+    }
+
+    /// Stores the hello frame of the data logger.
+    pub async fn update_hello(&self, hello: HelloInfo) {
+        let mut loggers = self.loggers.lock().await;
+        let data_logger_sn = hello.data_logger_sn;
+        loggers.entry(data_logger_sn).or_default().hello = Some(hello);
+        self.save(&loggers).await;
+    }
+
+    /// Stores the data frame of the inverter and updates its energy counters,
+    /// returning daily, monthly and yearly energy entities.
+    ///
+    /// The daily energy entity replaces the one from the data frame, as it
+    /// skips frames dated before the current day.
+    pub async fn update_data(&self, inverter: &InverterInfo, data: &Data) -> Vec<EntityType> {
+        let date = (data.year, data.month, data.day);
+        let mut loggers = self.loggers.lock().await;
+        let state = loggers.entry(inverter.data_logger_sn).or_default();
+
+        match InverterSnapshot::new(inverter, data) {
+            Ok(snapshot) => state.inverter = Some(snapshot),
+            Err(err) => error!("Error storing data of {} ({err})", inverter.serial),
+        }
+
+        let counters = state
+            .energy
+            .entry(inverter.serial.clone())
+            .and_modify(|counters| counters.update(date, data.daily_energy))
+            .or_insert_with(|| EnergyCounters::new(date, data.daily_energy));
+        let entities = counters.entities();
+
+        self.save(&loggers).await;
+        entities
+    }
+
+    /// Writes the state file, errors are only logged so readings are still
+    /// published.
+    async fn save(&self, loggers: &HashMap<u32, LoggerState>) {
+        if let Some(path) = &self.path {
+            if let Err(err) = save(path, loggers).await {
+                error!("Error writing state file {} ({err})", path.display());
+            }
+        }
+    }
+}
+
+/// Writes the state next to the file and renames it, so a crash never leaves
+/// the file truncated.
+async fn save(path: &Path, loggers: &HashMap<u32, LoggerState>) -> anyhow::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+
+    fs::write(&temporary_path, serde_json::to_vec(loggers)?).await?;
+    fs::rename(&temporary_path, path).await?;
+    Ok(())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HelloInfo, StateStore};
+    use crate::sink::tests::inverter;
+
+    #[tokio::test]
+    async fn persists_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let data = bincode::deserialize(&[0; 151]).unwrap();
+        let hello = HelloInfo {
+            data_logger_sn: 1744743503,
+            module_version: "LSW3_15_FFFF_1.0.34".to_string(),
+            mac_address: "98:d8:63:53:22:5a".to_string(),
+            ip_address: "10.0.0.64".to_string(),
+            signal_quality: 72,
+            upload_interval: 5,
+            last_seen: 1684481932,
+        };
+        let inverter = inverter();
+
+        let store = StateStore::load(Some(&path)).await.unwrap();
+        store.update_hello(hello.clone()).await;
+        store.update_data(&inverter, &data).await;
+
+        let store = StateStore::load(Some(&path)).await.unwrap();
+        let state = store.all().await.remove(&1744743503).unwrap();
+        assert_eq!(state.hello, Some(hello));
+        assert_eq!(state.inverter.unwrap().serial, "sf4es003m4c058");
+        assert!(state.energy.contains_key("sf4es003m4c058"));
+    }
+}
@@ -0,0 +1,139 @@
+use num_traits::FromPrimitive;
+
+#[derive(Primitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InverterStatus {
+    Waiting = 0,
+    Checking = 1,
+    Normal = 2,
+    Fault = 3,
+    PermanentFault = 4,
+}
+
+impl InverterStatus {
+    pub const OPTIONS: [&'static str; 6] = [
+        "waiting",
+        "checking",
+        "normal",
+        "fault",
+        "permanent_fault",
+        "unknown",
+    ];
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        InverterStatus::from_u16(code)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InverterStatus::Waiting => "waiting",
+            InverterStatus::Checking => "checking",
+            InverterStatus::Normal => "normal",
+            InverterStatus::Fault => "fault",
+            InverterStatus::PermanentFault => "permanent_fault",
+        }
+    }
+
+    pub fn is_fault(&self) -> bool {
+        matches!(self, InverterStatus::Fault | InverterStatus::PermanentFault)
+    }
+}
+
+/// Fault names as listed in the Sofar user manual, indexed by fault ID.
+///
+/// Fault ID `n` is reported as bit `(n - 1) % 8` of fault byte `(n - 1) / 8`.
+const FAULTS: [(u8, &str); 51] = [
+    (1, "Grid over voltage"),
+    (2, "Grid under voltage"),
+    (3, "Grid over frequency"),
+    (4, "Grid under frequency"),
+    (5, "PV under voltage"),
+    (6, "Grid low voltage ride through"),
+    (9, "PV over voltage"),
+    (10, "PV input current unbalanced"),
+    (11, "PV input mode misconfigured"),
+    (12, "Ground fault circuit interrupter fault"),
+    (13, "Phase sequence fault"),
+    (14, "Hardware boost over current"),
+    (15, "Hardware AC over current"),
+    (16, "AC current too high"),
+    (17, "Grid current sampling error"),
+    (18, "DCI sampling error"),
+    (19, "Grid voltage sampling error"),
+    (20, "GFCI device sampling error"),
+    (21, "Main chip fault"),
+    (22, "Hardware auxiliary power fault"),
+    (23, "Bus voltage zero"),
+    (24, "Output current unbalanced"),
+    (25, "Bus under voltage"),
+    (26, "Bus over voltage"),
+    (27, "Bus voltage unbalanced"),
+    (28, "DCI too high"),
+    (29, "Grid current too high"),
+    (30, "Input current too high"),
+    (49, "Consistency fault: grid voltage"),
+    (50, "Consistency fault: grid frequency"),
+    (51, "Consistency fault: DCI"),
+    (52, "Consistency fault: GFCI"),
+    (53, "SPI communication lost"),
+    (54, "SCI communication lost"),
+    (55, "Relay test failed"),
+    (56, "Insulation resistance too low"),
+    (57, "Inverter temperature too high"),
+    (58, "Boost temperature too high"),
+    (59, "Environment temperature too high"),
+    (60, "PE not connected"),
+    (65, "Unrecoverable hardware AC over current"),
+    (66, "Unrecoverable bus over voltage"),
+    (67, "Unrecoverable output current unbalanced"),
+    (68, "Unrecoverable input current unbalanced"),
+    (69, "Unrecoverable bus voltage unbalanced"),
+    (70, "Unrecoverable instant over current"),
+    (71, "Unrecoverable PV input mode misconfigured"),
+    (74, "Unrecoverable input over current"),
+    (75, "Unrecoverable EEPROM write fault"),
+    (76, "Unrecoverable EEPROM read fault"),
+    (77, "Unrecoverable relay fault"),
+];
+
+/// Decodes the raw fault bytes into a list of named active faults.
+pub fn active_faults(fault_codes: &[u8]) -> Vec<String> {
+    fault_codes
+        .iter()
+        .enumerate()
+        .flat_map(|(byte, bits)| {
+            (0..8)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| (byte * 8 + bit + 1) as u8)
+        })
+        .map(
+            |id| match FAULTS.iter().find(|(fault_id, _)| *fault_id == id) {
+                Some((_, name)) => format!("ID{id:02} {name}"),
+                None => format!("ID{id:02} Unknown fault"),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::active_faults;
+
+    #[test]
+    fn decodes_fault_bits() {
+        let faults = active_faults(&[0b0000_0011, 0, 0, 0, 0, 0, 0b1000_0000, 0, 0, 0]);
+
+        assert_eq!(
+            faults,
+            vec![
+                "ID01 Grid over voltage",
+                "ID02 Grid under voltage",
+                "ID56 Insulation resistance too low",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_faults() {
+        assert!(active_faults(&[0; 10]).is_empty());
+    }
+}
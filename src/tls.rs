@@ -0,0 +1,110 @@
+use crate::config::Config;
+use anyhow::{anyhow, Context};
+use rumqttc::TlsConfiguration;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use rustls_pemfile::Item;
+use std::{fs::File, io::BufReader, path::Path, sync::Arc, time::SystemTime};
+use tracing::warn;
+
+/// Builds rustls configuration for the MQTT connection.
+///
+/// Server certificates are verified against the configured CA file, or the
+/// system trust store when no CA file is given. Client certificate is sent only
+/// when both certificate and key files are configured.
+pub fn tls_configuration(config: &Config) -> anyhow::Result<TlsConfiguration> {
+    let mut root_store = RootCertStore::empty();
+
+    match &config.mqtt_ca_file {
+        Some(ca_file) => {
+            let (added, _) = root_store.add_parsable_certificates(&read_certificates(ca_file)?);
+            if added == 0 {
+                return Err(anyhow!("No valid CA certificates found in {ca_file}"));
+            }
+        }
+        None => {
+            let certificates = rustls_native_certs::load_native_certs()
+                .context("Error loading system CA certificates")?;
+            let certificates: Vec<Vec<u8>> = certificates.into_iter().map(|c| c.0).collect();
+            root_store.add_parsable_certificates(&certificates);
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+
+    let mut client_config = match (&config.mqtt_client_cert_file, &config.mqtt_client_key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let certificates = read_certificates(cert_file)?
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            builder
+                .with_single_cert(certificates, read_private_key(key_file)?)
+                .context("Invalid MQTT client certificate or key")?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(anyhow!(
+                "Both MQTT client certificate and key have to be configured"
+            ))
+        }
+    };
+
+    if config.mqtt_tls_insecure {
+        warn!("MQTT broker certificate verification is disabled");
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    Ok(TlsConfiguration::Rustls(Arc::new(client_config)))
+}
+
+fn read_pem_items(path: impl AsRef<Path>) -> anyhow::Result<Vec<Item>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Error opening {}", path.display()))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Error reading PEM file {}", path.display()))
+}
+
+fn read_certificates(path: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    Ok(read_pem_items(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(certificate) => Some(certificate),
+            _ => None,
+        })
+        .collect())
+}
+
+fn read_private_key(path: &str) -> anyhow::Result<PrivateKey> {
+    read_pem_items(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {path}"))
+}
+
+/// Accepts any server certificate, meant only for testing setups.
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}